@@ -0,0 +1,38 @@
+use rand::Rng;
+use tokio::time::Duration;
+
+/// Exponential backoff with jitter so that many clients don't hit a
+/// recovering server at the very same moment.
+#[derive(Debug)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Backoff {
+            initial_delay,
+            max_delay,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, doubling with every attempt up
+    /// to the maximum. The delay is randomized between half and the
+    /// full value.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max_delay);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
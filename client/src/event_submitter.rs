@@ -1,18 +1,29 @@
 extern crate protocol as proto;
 
+use crate::backoff::Backoff;
+use crate::spool::{spool_batches, take_spooled_batches};
 use crate::ClientCli;
 
 use proto::event_service_client::EventServiceClient;
 
-use tc_core::get_initial_state;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tc_core::{
+    get_initial_state, save_settings, wait_for_shutdown, CollectorRegistry, LocalSettings,
+    MetricAggregator,
+};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::codegen::InterceptedService;
 use tonic::metadata::{Ascii, MetadataValue};
 use tonic::service::Interceptor;
-use tonic::transport::Channel;
-use tonic::Status;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Code, Status};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
+#[derive(Clone)]
 struct InsertAuthTokenInterceptor {
     token: MetadataValue<Ascii>,
 }
@@ -26,30 +37,103 @@ impl Interceptor for InsertAuthTokenInterceptor {
     }
 }
 
+/// Metadata key in which the server tells us how long to back off
+const RETRY_AFTER_MS_HEADER: &str = "retry-after-ms";
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Streams are closed after this long so that the server acknowledges
+/// the batches sent over them
+const STREAM_DURATION: Duration = Duration::from_secs(10);
+
+/// Errors which won't go away by trying again
+fn is_fatal(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unauthenticated
+            | Code::PermissionDenied
+            | Code::AlreadyExists
+            | Code::InvalidArgument
+            | Code::Unimplemented
+    )
+}
+
+/// Waits as long as the server asks us to if we are rate limited
+fn get_server_retry_delay(status: &Status) -> Option<Duration> {
+    if status.code() != Code::ResourceExhausted {
+        return None;
+    }
+
+    status
+        .metadata()
+        .get(RETRY_AFTER_MS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Duration::from_millis)
+}
+
 pub struct EventSubmitter {
     client: EventServiceClient<InterceptedService<Channel, InsertAuthTokenInterceptor>>,
     submission_handler: Option<tokio::task::JoinHandle<()>>,
-    machine_id: i64,
+    settings: LocalSettings,
+    settings_filepath: PathBuf,
+    spool_filepath: PathBuf,
+    shutdown_timeout: Duration,
+    backoff: Backoff,
+    /// Cleared once the server turns out to not support streaming
+    use_streaming: bool,
+    /// Sequence number of the last batch, see `number_batch`
+    next_sequence: i64,
+    local_metrics: MetricAggregator,
 }
 
 impl Drop for EventSubmitter {
     fn drop(&mut self) {
-        match self.submission_handler {
-            Some(ref mut submission_handler) => {
-                submission_handler.abort();
-            }
-            None => {}
+        if let Some(ref mut submission_handler) = self.submission_handler {
+            submission_handler.abort();
         }
     }
 }
 
 impl EventSubmitter {
-    pub async fn new(cli: ClientCli, machine_id: i64, token: String) -> Self {
-        let channel = Channel::from_shared(format!("{}:{}", cli.address, cli.port).to_string())
-            .expect("Invalid server address")
-            .connect()
-            .await
-            .expect("Error connecting to the server");
+    pub async fn new(
+        cli: ClientCli,
+        settings: LocalSettings,
+        settings_filepath: PathBuf,
+        spool_filepath: PathBuf,
+        token: String,
+        local_metrics: MetricAggregator,
+    ) -> Self {
+        let mut endpoint =
+            Channel::from_shared(format!("{}:{}", cli.address, cli.port).to_string())
+                .expect("Invalid server address");
+
+        if let Some(tls_ca) = &cli.tls_ca {
+            let ca = tokio::fs::read(tls_ca)
+                .await
+                .expect("Could not read the TLS CA certificate");
+            let mut tls_config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+
+            if let Some(tls_domain) = &cli.tls_domain {
+                tls_config = tls_config.domain_name(tls_domain);
+            }
+
+            if let (Some(tls_cert), Some(tls_key)) = (&cli.tls_cert, &cli.tls_key) {
+                let cert = tokio::fs::read(tls_cert)
+                    .await
+                    .expect("Could not read the TLS client certificate");
+                let key = tokio::fs::read(tls_key)
+                    .await
+                    .expect("Could not read the TLS client key");
+                tls_config = tls_config.identity(Identity::from_pem(cert, key));
+            }
+
+            endpoint = endpoint
+                .tls_config(tls_config)
+                .expect("Invalid TLS configuration");
+        }
+
+        // Connecting lazily lets us start before the server is up,
+        // failed connections are retried like any other error.
+        let channel = endpoint.connect_lazy();
 
         let token_value: MetadataValue<_> = format!("Bearer {}", token).parse().unwrap();
 
@@ -61,70 +145,365 @@ impl EventSubmitter {
         Self {
             client: event_service,
             submission_handler: None,
-            machine_id,
+            settings,
+            settings_filepath,
+            spool_filepath,
+            shutdown_timeout: Duration::from_secs(cli.shutdown_timeout),
+            backoff: Backoff::new(
+                INITIAL_RETRY_DELAY,
+                Duration::from_secs(cli.max_retry_delay),
+            ),
+            use_streaming: true,
+            // Starting at the current time keeps the numbers increasing
+            // across restarts
+            next_sequence: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_micros() as i64),
+            local_metrics,
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), ()> {
+    /// Submits events until the collection stops, a shutdown is
+    /// requested or a fatal error occurs, such as the server rejecting
+    /// our token.
+    pub async fn start(&mut self, mut shutdown_rx: watch::Receiver<bool>) -> Result<(), Status> {
         // This loop retries to contact the server in case of any errors
         // during communication such as a disconnect
         loop {
-            match self.submit_events().await {
-                Ok(_) => {
-                    // The process runs indefinitely but
-                    // we assume getting here means
-                    // graceful termination for whatever
-                    // reason.
-                }
-                Err(_) => {
-                    // TODO return an error and log it here
-                    eprintln!("Waiting 5 seconds before trying again.");
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            match self.submit_events(&mut shutdown_rx).await {
+                Ok(_) => return Ok(()),
+                Err(status) if is_fatal(&status) => return Err(status),
+                Err(status) => {
+                    let backoff_delay = self.backoff.next_delay();
+                    let retry_delay = get_server_retry_delay(&status)
+                        .map_or(backoff_delay, |delay| delay.max(backoff_delay));
+                    info!(?retry_delay, "Waiting before trying again");
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry_delay) => {}
+                        _ = wait_for_shutdown(&mut shutdown_rx) => return Ok(()),
+                    }
                 }
             }
         }
     }
 
-    async fn submit_events(&mut self) -> Result<(), ()> {
+    async fn submit_events(
+        &mut self,
+        shutdown_rx: &mut watch::Receiver<bool>,
+    ) -> Result<(), Status> {
         let (tx, mut rx) = mpsc::channel::<proto::ChangeEventBatch>(32);
 
-        eprintln!("Fetching initial state");
-        let initial_state_result = self
-            .client
-            .initial_state(tonic::Request::new(
-                get_initial_state(self.machine_id).await,
-            ))
-            .await;
-        if let Err(err) = &initial_state_result {
-            eprintln!("Failed to get initial state: {}", err);
-            return Err(());
-        }
+        // Send what we could not send during the last shutdown first,
+        // so that the initial state already includes it
+        self.send_spooled_batches().await?;
 
-        let initial_state = initial_state_result.unwrap().into_inner();
-        eprintln!("Got initial state: {:?}", initial_state);
+        info!("Fetching initial state");
+        let initial_state_request =
+            tonic::Request::new(get_initial_state(self.settings.machine_id).await);
+        let initial_state_result = tokio::select! {
+            result = self
+                .client
+                .initial_state(initial_state_request)
+                .instrument(info_span!("initial_state", machine_id = self.settings.machine_id))
+                => result,
+            _ = wait_for_shutdown(shutdown_rx) => return Ok(()),
+        };
+        let initial_state = match initial_state_result {
+            Ok(initial_state) => initial_state.into_inner(),
+            Err(err) => {
+                error!(error = %err, "Failed to get initial state");
+                return Err(err);
+            }
+        };
+        debug!(?initial_state, "Got initial state");
+
+        // The server detected another machine using our id
+        if initial_state.assigned_machine_id != 0 {
+            warn!(
+                machine_id = self.settings.machine_id,
+                assigned_machine_id = initial_state.assigned_machine_id,
+                "Machine id is used by another machine, using the assigned one from now on"
+            );
+            self.settings.machine_id = initial_state.assigned_machine_id;
+            if let Err(err) = save_settings(&self.settings_filepath, &self.settings).await {
+                error!(error = %err, "Failed to save the new machine id to the settings");
+            }
+        }
 
         // collect data indefinitely and send data to the channel
-        let machine_id_clone = self.machine_id;
+        if let Some(submission_handler) = self.submission_handler.take() {
+            submission_handler.abort();
+        }
+        let registry = CollectorRegistry::with_defaults(
+            &initial_state,
+            &self.settings,
+            self.local_metrics.clone(),
+        );
+        let machine_id = self.settings.machine_id;
         self.submission_handler = Some(tokio::task::spawn(async move {
-            tc_core::collect_events(tx, initial_state, machine_id_clone).await;
+            tc_core::collect_events(tx, machine_id, registry).await;
         }));
 
+        if self.use_streaming {
+            match self.stream_events(&mut rx, shutdown_rx).await {
+                Err(status) if status.code() == Code::Unimplemented => {
+                    info!("Server does not support streaming, sending batches one by one");
+                    self.use_streaming = false;
+                    self.send_spooled_batches().await?;
+                }
+                result => return result,
+            }
+        }
+
         loop {
-            match rx.recv().await {
-                Some(event_batch) => {
-                    eprintln!("Sending events {:?}", event_batch);
-                    let request = tonic::Request::new(event_batch);
-                    match self.client.send_events(request).await {
-                        Ok(response) => {
-                            eprintln!("RESPONSE={:?}", response);
+            let event_batch = tokio::select! {
+                event_batch = rx.recv() => match event_batch {
+                    Some(event_batch) => event_batch,
+                    // the collection stopping closes the channel
+                    None => return Ok(()),
+                },
+                _ = wait_for_shutdown(shutdown_rx) => {
+                    self.flush(&mut rx).await;
+                    return Ok(());
+                }
+            };
+
+            let event_batch = self.number_batch(event_batch);
+            debug!(
+                n_events = event_batch.events.len(),
+                sequence = event_batch.sequence,
+                "Sending events"
+            );
+            let request = tonic::Request::new(event_batch.clone());
+            match self
+                .client
+                .send_events(request)
+                .instrument(info_span!(
+                    "send_events",
+                    machine_id = self.settings.machine_id
+                ))
+                .await
+            {
+                Ok(_) => {
+                    self.backoff.reset();
+                }
+                Err(e) => {
+                    // Sent again after reconnecting, the sequence number
+                    // lets the server skip it if it was stored after all
+                    error!(error = %e, "Error sending events, spooling them to disk");
+                    if let Err(err) = spool_batches(&self.spool_filepath, &[event_batch]).await {
+                        error!(error = %err, "Failed to spool events");
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Sends batches over streams which we close every
+    /// `STREAM_DURATION`, upon which the server acknowledges them once
+    /// they are stored. Batches the server didn't acknowledge are
+    /// spooled to disk if the stream breaks, so they are sent again
+    /// after reconnecting.
+    async fn stream_events(
+        &mut self,
+        rx: &mut mpsc::Receiver<proto::ChangeEventBatch>,
+        shutdown_rx: &mut watch::Receiver<bool>,
+    ) -> Result<(), Status> {
+        loop {
+            let (stream_tx, stream_rx) = mpsc::channel::<proto::ChangeEventBatch>(32);
+            let mut client = self.client.clone();
+            let mut ack = Box::pin(
+                async move { client.stream_events(ReceiverStream::new(stream_rx)).await }
+                    .instrument(info_span!(
+                        "stream_events",
+                        machine_id = self.settings.machine_id
+                    )),
+            );
+            let stream_end = tokio::time::sleep(STREAM_DURATION);
+            tokio::pin!(stream_end);
+
+            let mut unacked = vec![];
+            // whether we are done once the server acknowledged the stream
+            let mut finished = false;
+            // the server only answers early if something went wrong
+            let mut early_ack = None;
+
+            loop {
+                tokio::select! {
+                    event_batch = rx.recv() => match event_batch {
+                        Some(event_batch) => {
+                            let event_batch = self.number_batch(event_batch);
+                            debug!(
+                                n_events = event_batch.events.len(),
+                                sequence = event_batch.sequence,
+                                "Streaming events"
+                            );
+                            unacked.push(event_batch.clone());
+                            if stream_tx.send(event_batch).await.is_err() {
+                                break;
+                            }
+                        }
+                        // the collection stopping closes the channel
+                        None => {
+                            finished = true;
+                            break;
+                        }
+                    },
+                    _ = &mut stream_end => break,
+                    result = &mut ack => {
+                        early_ack = Some(result);
+                        break;
+                    }
+                    _ = wait_for_shutdown(shutdown_rx) => {
+                        if let Some(submission_handler) = self.submission_handler.take() {
+                            submission_handler.abort();
                         }
-                        Err(e) => {
-                            eprintln!("Error sending events: {:?}", e);
-                            return Err(());
+                        // Send what is still in the channel as well
+                        while let Ok(event_batch) = rx.try_recv() {
+                            let event_batch = self.number_batch(event_batch);
+                            unacked.push(event_batch.clone());
+                            if stream_tx.send(event_batch).await.is_err() {
+                                break;
+                            }
                         }
+                        finished = true;
+                        break;
                     }
                 }
-                None => {}
+            }
+
+            // Closing the stream makes the server acknowledge it
+            drop(stream_tx);
+            let result = match early_ack {
+                Some(result) => result,
+                None if finished => {
+                    info!(n_batches = unacked.len(), "Waiting for acknowledgement");
+                    match tokio::time::timeout(self.shutdown_timeout, ack).await {
+                        Ok(result) => result,
+                        Err(_) => Err(Status::deadline_exceeded(
+                            "Timed out waiting for acknowledgement",
+                        )),
+                    }
+                }
+                None => ack.await,
+            }
+            .map(tonic::Response::into_inner);
+
+            match &result {
+                Ok(ack) => {
+                    debug!(sequence = ack.sequence, "Events acknowledged");
+                    unacked.retain(|batch| batch.sequence > ack.sequence);
+                    self.backoff.reset();
+                }
+                Err(e) => error!(error = %e, "Error streaming events"),
+            }
+
+            if !unacked.is_empty() {
+                warn!(
+                    n_batches = unacked.len(),
+                    "Spooling unacknowledged batches to disk"
+                );
+                if let Err(err) = spool_batches(&self.spool_filepath, &unacked).await {
+                    error!(error = %err, "Failed to spool events");
+                }
+            }
+
+            match result {
+                Ok(_) if finished => return Ok(()),
+                Ok(_) => continue,
+                // Batches of the first stream are spooled already, thus
+                // nothing is lost falling back to unary calls
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Numbers the batch so that the server can tell if it is sent
+    /// again after a stream broke or a call failed
+    fn number_batch(
+        &mut self,
+        mut event_batch: proto::ChangeEventBatch,
+    ) -> proto::ChangeEventBatch {
+        self.next_sequence += 1;
+        event_batch.sequence = self.next_sequence;
+        event_batch
+    }
+
+    async fn send_spooled_batches(&mut self) -> Result<(), Status> {
+        let mut batches = match take_spooled_batches(&self.spool_filepath).await {
+            Ok(batches) => batches,
+            Err(err) => {
+                error!(error = %err, "Failed to read spooled events");
+                return Ok(());
+            }
+        };
+        if !batches.is_empty() {
+            info!(n_batches = batches.len(), "Sending spooled batches");
+        }
+
+        while !batches.is_empty() {
+            let request = tonic::Request::new(batches[0].clone());
+            if let Err(e) = self
+                .client
+                .send_events(request)
+                .instrument(info_span!(
+                    "send_events",
+                    machine_id = self.settings.machine_id
+                ))
+                .await
+            {
+                error!(error = %e, "Error sending spooled events");
+                if let Err(err) = spool_batches(&self.spool_filepath, &batches).await {
+                    error!(error = %err, "Failed to spool events");
+                }
+                return Err(e);
+            }
+            batches.remove(0);
+        }
+
+        Ok(())
+    }
+
+    /// Stops collecting and sends the batches still waiting in the
+    /// channel. Whatever can't be sent in time is spooled to disk.
+    async fn flush(&mut self, rx: &mut mpsc::Receiver<proto::ChangeEventBatch>) {
+        if let Some(submission_handler) = self.submission_handler.take() {
+            submission_handler.abort();
+        }
+
+        let mut batches = vec![];
+        while let Ok(event_batch) = rx.try_recv() {
+            batches.push(self.number_batch(event_batch));
+        }
+        info!(n_batches = batches.len(), "Flushing batches");
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while !batches.is_empty() {
+            let request = tonic::Request::new(batches[0].clone());
+            let send = self.client.send_events(request).instrument(info_span!(
+                "send_events",
+                machine_id = self.settings.machine_id
+            ));
+            match tokio::time::timeout_at(deadline, send).await {
+                Ok(Ok(_)) => {
+                    batches.remove(0);
+                }
+                Ok(Err(e)) => {
+                    error!(error = %e, "Error sending events");
+                    break;
+                }
+                Err(_) => {
+                    warn!("Timed out sending events");
+                    break;
+                }
+            }
+        }
+
+        if !batches.is_empty() {
+            warn!(n_batches = batches.len(), "Spooling batches to disk");
+            if let Err(err) = spool_batches(&self.spool_filepath, &batches).await {
+                error!(error = %err, "Failed to spool events");
             }
         }
     }
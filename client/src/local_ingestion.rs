@@ -0,0 +1,100 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tc_core::{parse_statsd_line, wait_for_shutdown, MetricAggregator};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{UdpSocket, UnixListener, UnixStream};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// Largest datagram a StatsD client may send
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Longest line accepted on the Unix socket
+const MAX_LINE_LENGTH: usize = 4096;
+
+fn record_lines(text: &str, aggregator: &MetricAggregator) {
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match parse_statsd_line(line) {
+            Ok(sample) => aggregator.record(sample),
+            Err(e) => debug!(error = %e, "Ignoring invalid metric"),
+        }
+    }
+}
+
+async fn read_connection(stream: UnixStream, aggregator: MetricAggregator) {
+    let mut reader = BufReader::new(stream);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        // A line without an end must not fill up our memory
+        let result = (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line)
+            .await;
+        match result {
+            Ok(0) => return,
+            Ok(len) if len == MAX_LINE_LENGTH && !line.ends_with(b"\n") => {
+                debug!("Line on metrics socket too long, closing connection");
+                return;
+            }
+            Ok(_) => record_lines(&String::from_utf8_lossy(&line), &aggregator),
+            Err(e) => {
+                debug!(error = %e, "Error reading from metrics socket");
+                return;
+            }
+        }
+    }
+}
+
+/// Accepts StatsD lines from local applications on a Unix socket,
+/// one metric per line, until shutdown.
+pub async fn listen_unix(
+    path: PathBuf,
+    aggregator: MetricAggregator,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> io::Result<()> {
+    // A previous run which did not shut down cleanly leaves the
+    // socket file behind
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&path)?;
+    info!(path = %path.display(), "Accepting metrics on Unix socket");
+
+    loop {
+        tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok((stream, _)) => {
+                    tokio::task::spawn(read_connection(stream, aggregator.clone()));
+                }
+                Err(e) => warn!(error = %e, "Failed to accept metrics connection"),
+            },
+            _ = wait_for_shutdown(&mut shutdown_rx) => break,
+        }
+    }
+
+    tokio::fs::remove_file(&path).await
+}
+
+/// Accepts StatsD datagrams, possibly several lines each, until
+/// shutdown.
+pub async fn listen_udp(
+    addr: SocketAddr,
+    aggregator: MetricAggregator,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    info!(%addr, "Accepting StatsD metrics");
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            datagram = socket.recv_from(&mut buf) => match datagram {
+                Ok((len, _)) => record_lines(&String::from_utf8_lossy(&buf[..len]), &aggregator),
+                Err(e) => warn!(error = %e, "Failed to receive StatsD metrics"),
+            },
+            _ = wait_for_shutdown(&mut shutdown_rx) => return Ok(()),
+        }
+    }
+}
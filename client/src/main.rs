@@ -1,13 +1,23 @@
 extern crate core;
 
 // mod
+mod backoff;
 mod env;
 mod event_submitter;
+mod local_ingestion;
+mod spool;
 
 use clap::Parser;
 use env::get_api_token;
 use event_submitter::EventSubmitter;
-use tc_core::{get_settings_filepath, load_settings};
+use local_ingestion::{listen_udp, listen_unix};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tc_core::{
+    get_metrics_socket_filepath, get_settings_filepath, get_spool_filepath, init_logging,
+    load_settings, spawn_shutdown_listener, MetricAggregator,
+};
+use tracing::{debug, error};
 // use tonic::metadata::MetadataValue;
 
 #[derive(Parser, Debug, Clone)]
@@ -19,39 +29,105 @@ pub struct ClientCli {
     address: String,
     #[clap(short = 'p', long, value_parser, default_value_t = 50055)]
     port: u16,
+    /// Maximum seconds to wait between attempts to reach the server
+    #[clap(long, value_parser, default_value_t = 300)]
+    max_retry_delay: u64,
+    /// Seconds to keep sending remaining events when shutting down
+    /// before spooling them to disk
+    #[clap(long, value_parser, default_value_t = 10)]
+    shutdown_timeout: u64,
+    /// CA certificate (PEM) the server certificate must be signed with.
+    /// Enables TLS, thus the address needs to use https.
+    #[clap(long, value_parser)]
+    tls_ca: Option<PathBuf>,
+    /// Domain name to verify the server certificate against
+    /// if it differs from the address
+    #[clap(long, value_parser, requires = "tls-ca")]
+    tls_domain: Option<String>,
+    /// Client certificate (PEM) for mutual TLS. Its common name must
+    /// be the machine id.
+    #[clap(long, value_parser, requires_all = &["tls-ca", "tls-key"])]
+    tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of the client certificate
+    #[clap(long, value_parser, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Log level such as info or debug, RUST_LOG takes precedence
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: String,
+    /// Log in JSON format
+    #[clap(long, value_parser)]
+    log_json: bool,
+    /// Unix socket on which local applications push metrics as StatsD
+    /// lines, defaults to metrics.sock in the XDG runtime directory
+    #[clap(long, value_parser)]
+    metrics_socket: Option<PathBuf>,
+    /// Don't accept metrics of local applications on a Unix socket
+    #[clap(long, value_parser, conflicts_with = "metrics-socket")]
+    no_metrics_socket: bool,
+    /// Localhost UDP port to accept StatsD metrics on
+    #[clap(long, value_parser)]
+    statsd_port: Option<u16>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = ClientCli::parse();
-    eprintln!("Cli config: {:?}", &cli);
+    init_logging(&cli.log_level, cli.log_json);
+    debug!(?cli, "Cli config");
     let api_token = get_api_token();
 
     let settings_filepath = get_settings_filepath().await;
     let settings = load_settings(&settings_filepath).await;
+    let spool_filepath = get_spool_filepath().await;
+    let shutdown_rx = spawn_shutdown_listener();
+
+    // Metrics of local applications are sent with the next batch
+    let local_metrics = MetricAggregator::default();
+    if !cli.no_metrics_socket {
+        let socket_path = match &cli.metrics_socket {
+            Some(path) => path.clone(),
+            None => get_metrics_socket_filepath().await,
+        };
+        let (aggregator, shutdown_rx) = (local_metrics.clone(), shutdown_rx.clone());
+        tokio::task::spawn(async move {
+            if let Err(e) = listen_unix(socket_path, aggregator, shutdown_rx).await {
+                error!(error = %e, "Failed to accept metrics on the Unix socket");
+            }
+        });
+    }
+    if let Some(statsd_port) = cli.statsd_port {
+        let addr = SocketAddr::from(([127, 0, 0, 1], statsd_port));
+        let (aggregator, shutdown_rx) = (local_metrics.clone(), shutdown_rx.clone());
+        tokio::task::spawn(async move {
+            if let Err(e) = listen_udp(addr, aggregator, shutdown_rx).await {
+                error!(error = %e, "Failed to accept StatsD metrics");
+            }
+        });
+    }
 
     // receive change events from a channel and send them to the
     // server.
     let send_handler = tokio::task::spawn(async move {
-        let mut submitter = EventSubmitter::new(cli.clone(), settings.machine_id, api_token).await;
-        match submitter.start().await {
-            Ok(_) => {
-                // graceful termination
-            }
-            Err(e) => {
-                eprintln!("Error submitting events: {:?}", e);
-            }
-        }
+        let mut submitter = EventSubmitter::new(
+            cli.clone(),
+            settings,
+            settings_filepath,
+            spool_filepath,
+            api_token,
+            local_metrics,
+        )
+        .await;
+        submitter.start(shutdown_rx).await
     });
 
-    match send_handler.await {
+    match send_handler.await? {
         Ok(_) => {
             // graceful termination
+            Ok(())
         }
         Err(e) => {
-            eprintln!("Error sending events: {:?}", e);
+            error!(error = %e, "Error submitting events");
+            Err(e.into())
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,54 @@
+extern crate protocol as proto;
+
+use prost::Message;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// Appends batches to the spool file so that they can be sent after
+/// the next start.
+pub async fn spool_batches(
+    spool_filepath: &Path,
+    batches: &[proto::ChangeEventBatch],
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![];
+    for batch in batches {
+        // unwrapping is safe as a Vec grows as needed
+        batch.encode_length_delimited(&mut buffer).unwrap();
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(spool_filepath)
+        .await?;
+    file.write_all(&buffer).await?;
+    file.flush().await
+}
+
+/// Reads and removes all batches from the spool file.
+pub async fn take_spooled_batches(
+    spool_filepath: &Path,
+) -> Result<Vec<proto::ChangeEventBatch>, std::io::Error> {
+    let contents = match tokio::fs::read(spool_filepath).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut batches = vec![];
+    let mut buffer = contents.as_slice();
+    while !buffer.is_empty() {
+        match proto::ChangeEventBatch::decode_length_delimited(&mut buffer) {
+            Ok(batch) => batches.push(batch),
+            Err(err) => {
+                // A partially written batch from being killed midway
+                warn!(error = %err, "Dropping corrupt rest of the spool file");
+                break;
+            }
+        }
+    }
+
+    tokio::fs::remove_file(spool_filepath).await?;
+    Ok(batches)
+}
@@ -1,9 +1,16 @@
 extern crate tonic_build;
 
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=migrations");
 
+    // The descriptor set allows the server to offer gRPC reflection
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
     tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("change_events_descriptor.bin"))
         .build_server(true)
         .build_client(true)
         .compile(&["protobuf/change_events.proto"], &["protobuf"])
@@ -2,6 +2,9 @@ use std::hash::Hash;
 
 tonic::include_proto!("change_events");
 
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("change_events_descriptor");
+
 pub trait ToEvent {
     fn to_change_event(&self, event_type: EventType) -> ChangeEvent;
 }
@@ -21,6 +24,10 @@ macro_rules! impl_to_event {
 
 impl_to_event!(NetworkDevice);
 impl_to_event!(Mount);
+impl_to_event!(Process);
+impl_to_event!(Cgroup);
+impl_to_event!(Metric);
+impl_to_event!(ProbeResult);
 
 impl Eq for NetworkDevice {}
 
@@ -72,12 +79,96 @@ where
         let fs_type: String = row.try_get("fs_type")?;
         let free: i64 = row.try_get("free")?;
         let total: i64 = row.try_get("total")?;
+        let files: i64 = row.try_get("files")?;
+        let files_avail: i64 = row.try_get("files_avail")?;
         ::std::result::Result::Ok(Mount {
             device_name,
             mount_location,
             fs_type,
             free,
             total,
+            files,
+            files_avail,
+        })
+    }
+}
+
+// As we cannot apply the deconstruction Macro on protobuf
+// entities since they are automatically generated, we do
+// it here manually.
+impl<'a, R: ::sqlx::Row> ::sqlx::FromRow<'a, R> for Process
+where
+    &'a ::std::primitive::str: ::sqlx::ColumnIndex<R>,
+    String: ::sqlx::decode::Decode<'a, R::Database>,
+    String: ::sqlx::types::Type<R::Database>,
+    Vec<i64>: ::sqlx::decode::Decode<'a, R::Database>,
+    Vec<i64>: ::sqlx::types::Type<R::Database>,
+{
+    fn from_row(row: &'a R) -> ::sqlx::Result<Self> {
+        let name: String = row.try_get("name")?;
+        let pids: Vec<i64> = row.try_get("pids")?;
+        ::std::result::Result::Ok(Process { name, pids })
+    }
+}
+
+// As we cannot apply the deconstruction Macro on protobuf
+// entities since they are automatically generated, we do
+// it here manually.
+impl<'a, R: ::sqlx::Row> ::sqlx::FromRow<'a, R> for Cgroup
+where
+    &'a ::std::primitive::str: ::sqlx::ColumnIndex<R>,
+    String: ::sqlx::decode::Decode<'a, R::Database>,
+    String: ::sqlx::types::Type<R::Database>,
+    i64: ::sqlx::decode::Decode<'a, R::Database>,
+    i64: ::sqlx::types::Type<R::Database>,
+{
+    fn from_row(row: &'a R) -> ::sqlx::Result<Self> {
+        let path: String = row.try_get("path")?;
+        let cpu_usage_usec: i64 = row.try_get("cpu_usage_usec")?;
+        let memory_current: i64 = row.try_get("memory_current")?;
+        let memory_max: i64 = row.try_get("memory_max")?;
+        let io_read_bytes: i64 = row.try_get("io_read_bytes")?;
+        let io_write_bytes: i64 = row.try_get("io_write_bytes")?;
+        ::std::result::Result::Ok(Cgroup {
+            path,
+            cpu_usage_usec,
+            memory_current,
+            memory_max,
+            io_read_bytes,
+            io_write_bytes,
+        })
+    }
+}
+
+// As we cannot apply the deconstruction Macro on protobuf
+// entities since they are automatically generated, we do
+// it here manually.
+impl<'a, R: ::sqlx::Row> ::sqlx::FromRow<'a, R> for ProbeResult
+where
+    &'a ::std::primitive::str: ::sqlx::ColumnIndex<R>,
+    String: ::sqlx::decode::Decode<'a, R::Database>,
+    String: ::sqlx::types::Type<R::Database>,
+    bool: ::sqlx::decode::Decode<'a, R::Database>,
+    bool: ::sqlx::types::Type<R::Database>,
+    f64: ::sqlx::decode::Decode<'a, R::Database>,
+    f64: ::sqlx::types::Type<R::Database>,
+    i32: ::sqlx::decode::Decode<'a, R::Database>,
+    i32: ::sqlx::types::Type<R::Database>,
+{
+    fn from_row(row: &'a R) -> ::sqlx::Result<Self> {
+        let name: String = row.try_get("name")?;
+        let target: String = row.try_get("target")?;
+        let success: bool = row.try_get("success")?;
+        let latency_ms: f64 = row.try_get("latency_ms")?;
+        let status_code: i32 = row.try_get("status_code")?;
+        let error: String = row.try_get("error")?;
+        ::std::result::Result::Ok(ProbeResult {
+            name,
+            target,
+            success,
+            latency_ms,
+            status_code,
+            error,
         })
     }
 }
@@ -0,0 +1,32 @@
+-- Processes
+-- Watched processes which are currently running on a machine.
+CREATE TABLE IF NOT EXISTS processes (
+    machine_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    pids BIGINT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX processes_index
+    ON processes (machine_id, name);
+
+CREATE TRIGGER set_timestamp
+    BEFORE UPDATE ON processes
+    FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+-- Process Transitions
+-- History of watched processes appearing (ADD), restarting (UPDATE)
+-- or disappearing (DELETE).
+CREATE TABLE IF NOT EXISTS process_transitions (
+    id BIGSERIAL PRIMARY KEY,
+    machine_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    transition TEXT NOT NULL,
+    pids BIGINT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX process_transitions_machine_index
+    ON process_transitions (machine_id, name);
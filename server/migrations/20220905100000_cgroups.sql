@@ -0,0 +1,22 @@
+-- Cgroup Statistics
+-- Resource usage of each cgroup v2 on a machine such as
+-- systemd services or containers.
+CREATE TABLE IF NOT EXISTS cgroup_statistics (
+    machine_id BIGINT NOT NULL,
+    path TEXT NOT NULL,
+    cpu_usage_usec BIGINT NOT NULL,
+    memory_current BIGINT NOT NULL,
+    memory_max BIGINT NOT NULL,
+    io_read_bytes BIGINT NOT NULL,
+    io_write_bytes BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX cgroup_statistics_index
+    ON cgroup_statistics (machine_id, path);
+
+CREATE TRIGGER set_timestamp
+    BEFORE UPDATE ON cgroup_statistics
+    FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
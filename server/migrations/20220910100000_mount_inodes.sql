@@ -0,0 +1,5 @@
+-- Mount Inodes
+-- Used and available inodes of a mount.
+ALTER TABLE mounts
+    ADD COLUMN IF NOT EXISTS files BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS files_avail BIGINT NOT NULL DEFAULT 0;
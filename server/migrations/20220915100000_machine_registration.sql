@@ -0,0 +1,7 @@
+-- Machine Registration
+-- Details to recognize whether a machine id is claimed by another host.
+ALTER TABLE machines
+    ALTER COLUMN ip DROP NOT NULL,
+    ADD COLUMN IF NOT EXISTS hostname TEXT NOT NULL DEFAULT '',
+    ADD COLUMN IF NOT EXISTS boot_time TIMESTAMPTZ NOT NULL DEFAULT to_timestamp(0),
+    ADD COLUMN IF NOT EXISTS token_hash TEXT NOT NULL DEFAULT '';
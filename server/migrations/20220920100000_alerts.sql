@@ -0,0 +1,26 @@
+-- Alerts
+-- Conditions of alert rules which held long enough to fire. An alert
+-- is open until it is resolved.
+CREATE TABLE IF NOT EXISTS alerts (
+    id BIGSERIAL PRIMARY KEY,
+    rule TEXT NOT NULL,
+    machine_id BIGINT NOT NULL,
+    -- e.g. the mount location for disk rules, empty for machine wide ones
+    series TEXT NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    threshold DOUBLE PRECISION NOT NULL,
+    fired_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolved_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- A condition alerts once until it is resolved
+CREATE UNIQUE INDEX alerts_open_index
+    ON alerts (rule, machine_id, series)
+    WHERE resolved_at IS NULL;
+
+CREATE TRIGGER set_timestamp
+    BEFORE UPDATE ON alerts
+    FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
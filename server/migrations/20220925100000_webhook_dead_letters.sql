@@ -0,0 +1,17 @@
+-- Webhook Dead Letters
+-- Notifications which could not be delivered even after retrying,
+-- kept to deliver them by hand or to debug the receiver.
+CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    error TEXT NOT NULL,
+    attempts INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER set_timestamp
+    BEFORE UPDATE ON webhook_dead_letters
+    FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
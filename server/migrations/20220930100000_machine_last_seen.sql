@@ -0,0 +1,11 @@
+-- Machine Status
+-- When a machine reported last and whether it is still reporting in
+-- time, i.e. 'online', 'stale' or 'offline'.
+ALTER TABLE machines
+    ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ,
+    -- seconds between two reports of the machine
+    ADD COLUMN IF NOT EXISTS report_interval INTEGER NOT NULL DEFAULT 5,
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'online';
+
+CREATE INDEX machine_last_seen_index
+    ON machines (last_seen);
@@ -0,0 +1,54 @@
+-- Statistics Rollups
+-- Raw statistics are only kept for a while, older data survives as
+-- per minute and per hour aggregates which are kept longer.
+CREATE INDEX IF NOT EXISTS cpu_statistics_created_at_index
+    ON cpu_statistics (machine_id, created_at);
+CREATE INDEX IF NOT EXISTS memory_statistics_created_at_index
+    ON memory_statistics (machine_id, created_at);
+
+CREATE TABLE IF NOT EXISTS cpu_statistics_1m (
+    machine_id BIGINT NOT NULL,
+    bucket TIMESTAMPTZ NOT NULL,
+    usage_avg FLOAT,
+    usage_max FLOAT,
+    temperature_avg FLOAT,
+    temperature_max FLOAT,
+    n_samples BIGINT NOT NULL,
+    PRIMARY KEY (machine_id, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS cpu_statistics_1h (
+    machine_id BIGINT NOT NULL,
+    bucket TIMESTAMPTZ NOT NULL,
+    usage_avg FLOAT,
+    usage_max FLOAT,
+    temperature_avg FLOAT,
+    temperature_max FLOAT,
+    n_samples BIGINT NOT NULL,
+    PRIMARY KEY (machine_id, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS memory_statistics_1m (
+    machine_id BIGINT NOT NULL,
+    bucket TIMESTAMPTZ NOT NULL,
+    total BIGINT NOT NULL,
+    free_avg BIGINT NOT NULL,
+    free_min BIGINT NOT NULL,
+    n_samples BIGINT NOT NULL,
+    PRIMARY KEY (machine_id, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS memory_statistics_1h (
+    machine_id BIGINT NOT NULL,
+    bucket TIMESTAMPTZ NOT NULL,
+    total BIGINT NOT NULL,
+    free_avg BIGINT NOT NULL,
+    free_min BIGINT NOT NULL,
+    n_samples BIGINT NOT NULL,
+    PRIMARY KEY (machine_id, bucket)
+);
+
+CREATE INDEX cpu_statistics_1m_bucket_index ON cpu_statistics_1m (bucket);
+CREATE INDEX cpu_statistics_1h_bucket_index ON cpu_statistics_1h (bucket);
+CREATE INDEX memory_statistics_1m_bucket_index ON memory_statistics_1m (bucket);
+CREATE INDEX memory_statistics_1h_bucket_index ON memory_statistics_1h (bucket);
@@ -0,0 +1,70 @@
+-- Hypertables
+-- Partitions the sample tables by time and compresses older chunks
+-- if TimescaleDB is available. Plain Postgres keeps regular tables
+-- with the same (machine_id, time) indexes.
+CREATE INDEX IF NOT EXISTS process_transitions_created_at_index
+    ON process_transitions (machine_id, created_at);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb') THEN
+        RAISE NOTICE 'TimescaleDB is not available, keeping plain tables';
+        RETURN;
+    END IF;
+
+    CREATE EXTENSION IF NOT EXISTS timescaledb;
+
+    -- Unique constraints of hypertables must contain the time column,
+    -- the ids are not referenced anywhere
+    ALTER TABLE cpu_statistics DROP CONSTRAINT IF EXISTS cpu_statistics_pkey;
+    ALTER TABLE memory_statistics DROP CONSTRAINT IF EXISTS memory_statistics_pkey;
+    ALTER TABLE process_transitions DROP CONSTRAINT IF EXISTS process_transitions_pkey;
+
+    PERFORM create_hypertable('cpu_statistics', 'created_at',
+        chunk_time_interval => INTERVAL '1 day', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('memory_statistics', 'created_at',
+        chunk_time_interval => INTERVAL '1 day', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('process_transitions', 'created_at',
+        chunk_time_interval => INTERVAL '7 days', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('cpu_statistics_1m', 'bucket',
+        chunk_time_interval => INTERVAL '7 days', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('memory_statistics_1m', 'bucket',
+        chunk_time_interval => INTERVAL '7 days', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('cpu_statistics_1h', 'bucket',
+        chunk_time_interval => INTERVAL '30 days', migrate_data => TRUE, if_not_exists => TRUE);
+    PERFORM create_hypertable('memory_statistics_1h', 'bucket',
+        chunk_time_interval => INTERVAL '30 days', migrate_data => TRUE, if_not_exists => TRUE);
+
+    -- Chunks are compressed once nothing writes to them anymore,
+    -- rollups rewrite their most recent bucket only
+    ALTER TABLE cpu_statistics SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'created_at DESC');
+    ALTER TABLE memory_statistics SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'created_at DESC');
+    ALTER TABLE process_transitions SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'created_at DESC');
+    ALTER TABLE cpu_statistics_1m SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'bucket DESC');
+    ALTER TABLE memory_statistics_1m SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'bucket DESC');
+    ALTER TABLE cpu_statistics_1h SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'bucket DESC');
+    ALTER TABLE memory_statistics_1h SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id',
+        timescaledb.compress_orderby = 'bucket DESC');
+
+    PERFORM add_compression_policy('cpu_statistics', INTERVAL '2 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('memory_statistics', INTERVAL '2 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('process_transitions', INTERVAL '14 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('cpu_statistics_1m', INTERVAL '14 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('memory_statistics_1m', INTERVAL '14 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('cpu_statistics_1h', INTERVAL '60 days', if_not_exists => TRUE);
+    PERFORM add_compression_policy('memory_statistics_1h', INTERVAL '60 days', if_not_exists => TRUE);
+END
+$$;
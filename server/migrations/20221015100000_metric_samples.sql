@@ -0,0 +1,34 @@
+-- Metric Samples
+-- Application specific metrics sent as generic Metric events, so that
+-- new kinds of metrics need neither protocol changes nor new tables.
+CREATE TABLE IF NOT EXISTS metric_samples (
+    machine_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    labels JSONB NOT NULL DEFAULT '{}',
+    value DOUBLE PRECISION NOT NULL,
+    unit TEXT NOT NULL DEFAULT '',
+    -- GAUGE or COUNTER
+    kind TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX metric_samples_index
+    ON metric_samples (machine_id, name, created_at);
+
+CREATE INDEX metric_samples_labels_index
+    ON metric_samples USING GIN (labels);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        RETURN;
+    END IF;
+
+    PERFORM create_hypertable('metric_samples', 'created_at',
+        chunk_time_interval => INTERVAL '1 day', migrate_data => TRUE, if_not_exists => TRUE);
+    ALTER TABLE metric_samples SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id, name',
+        timescaledb.compress_orderby = 'created_at DESC');
+    PERFORM add_compression_policy('metric_samples', INTERVAL '2 days', if_not_exists => TRUE);
+END
+$$;
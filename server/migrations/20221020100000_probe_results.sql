@@ -0,0 +1,36 @@
+-- Probe Results
+-- Outcome of every synthetic HTTP or TCP check a client ran against
+-- a service, kept as a time series.
+CREATE TABLE IF NOT EXISTS probe_results (
+    machine_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    -- URL or host:port
+    target TEXT NOT NULL,
+    success BOOLEAN NOT NULL,
+    latency_ms DOUBLE PRECISION NOT NULL,
+    -- 0 for TCP probes or without a response
+    status_code INTEGER NOT NULL,
+    error TEXT NOT NULL DEFAULT '',
+    -- last row of a probe removed from the settings of the client, so
+    -- that its final result doesn't count as current
+    removed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX probe_results_index
+    ON probe_results (machine_id, name, created_at);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        RETURN;
+    END IF;
+
+    PERFORM create_hypertable('probe_results', 'created_at',
+        chunk_time_interval => INTERVAL '1 day', migrate_data => TRUE, if_not_exists => TRUE);
+    ALTER TABLE probe_results SET (timescaledb.compress,
+        timescaledb.compress_segmentby = 'machine_id, name',
+        timescaledb.compress_orderby = 'created_at DESC');
+    PERFORM add_compression_policy('probe_results', INTERVAL '2 days', if_not_exists => TRUE);
+END
+$$;
@@ -0,0 +1,665 @@
+extern crate protocol as proto;
+
+use super::database::{Alert, Database};
+use super::notifier::{Notification, Notifier};
+use proto::{change_event::Event, ChangeEventBatch, EventType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+/// Values the rules can check, ratios are between 0 and 1
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    CpuUsage,
+    CpuTemp,
+    MemoryUsage,
+    /// Per mount
+    DiskUsage,
+    /// Per mount
+    InodeUsage,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Comparison {
+    #[default]
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = "<")]
+    Below,
+}
+
+/// E.g. fire if the cpu usage is above 0.95 for 600 seconds
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: AlertMetric,
+    #[serde(default)]
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// Seconds the condition must hold before the alert fires
+    #[serde(rename = "for", default)]
+    pub for_secs: u64,
+}
+
+impl AlertRule {
+    fn is_violated(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct AlertRulesFile {
+    #[serde(default)]
+    rules: Vec<AlertRule>,
+}
+
+pub async fn load_alert_rules(path: &Path) -> Result<Vec<AlertRule>, Box<dyn std::error::Error>> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let rules_file: AlertRulesFile = toml::from_str(&content)?;
+    Ok(rules_file.rules)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AlertKey {
+    rule: usize,
+    machine_id: i64,
+    series: String,
+}
+
+#[derive(Debug)]
+struct AlertState {
+    value: f64,
+    /// Set while the condition holds
+    violated_since: Option<Instant>,
+    firing: bool,
+}
+
+#[derive(Debug, Default)]
+struct EngineState {
+    alerts: HashMap<AlertKey, AlertState>,
+    /// When each machine sent its last batch, conditions only count as
+    /// holding until then
+    last_seen: HashMap<i64, Instant>,
+}
+
+enum Transition {
+    Fire(Alert),
+    Resolve(Alert),
+}
+
+impl AlertMetric {
+    /// Whether the values are kept in the statistics tables
+    fn is_stored(&self) -> bool {
+        matches!(
+            self,
+            AlertMetric::CpuUsage | AlertMetric::CpuTemp | AlertMetric::MemoryUsage
+        )
+    }
+}
+
+/// Evaluates the alert rules against incoming values. Each condition
+/// fires once and stays open until it resolves.
+#[derive(Debug)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    state: Mutex<EngineState>,
+    db: Arc<dyn Database>,
+    notifier: Notifier,
+}
+
+impl AlertEngine {
+    /// Picks up alerts which are still open from a previous run and
+    /// replays the stored statistics, so that e.g. the cpu being pegged
+    /// for a while before a restart counts. Alerts of rules which were
+    /// removed in the meantime are resolved.
+    pub async fn new(rules: Vec<AlertRule>, db: Arc<dyn Database>, notifier: Notifier) -> Self {
+        let engine = AlertEngine {
+            rules,
+            state: Mutex::new(EngineState::default()),
+            db,
+            notifier,
+        };
+
+        let mut transitions = vec![];
+        match engine.db.fetch_open_alerts().await {
+            Ok(alerts) => {
+                let mut state = engine.lock_state();
+                for alert in alerts {
+                    let rule = match engine.rules.iter().position(|rule| rule.name == alert.rule) {
+                        Some(rule) => rule,
+                        None => {
+                            info!(rule = %alert.rule, "Resolving alert of a removed rule");
+                            transitions.push(Transition::Resolve(alert));
+                            continue;
+                        }
+                    };
+                    state.alerts.insert(
+                        AlertKey {
+                            rule,
+                            machine_id: alert.machine_id,
+                            series: alert.series,
+                        },
+                        AlertState {
+                            value: alert.value,
+                            violated_since: Some(Instant::now()),
+                            firing: true,
+                        },
+                    );
+                }
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to fetch open alerts from database");
+            }
+        }
+
+        transitions.extend(engine.replay_statistics().await);
+        engine.persist(transitions).await;
+        engine
+    }
+
+    /// Feeds the statistics as far back as the longest `for` of the
+    /// rules on stored metrics through the rules. Mounts are observed
+    /// again once their machine requests its initial state.
+    async fn replay_statistics(&self) -> Vec<Transition> {
+        let replay_secs = self
+            .rules
+            .iter()
+            .filter(|rule| rule.metric.is_stored())
+            .map(|rule| rule.for_secs)
+            .max()
+            .unwrap_or(0);
+        if replay_secs == 0 {
+            return vec![];
+        }
+
+        let now = Instant::now();
+        let now_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        let statistics = match self
+            .db
+            .fetch_recent_statistics(now_secs - replay_secs as i64)
+            .await
+        {
+            Ok(statistics) => statistics,
+            Err(e) => {
+                error!(error = %e, "Failed to fetch statistics to replay from database");
+                return vec![];
+            }
+        };
+        debug!(n_samples = statistics.len(), "Replaying statistics");
+
+        let mut state = self.lock_state();
+        let mut transitions = vec![];
+        for (time, batch) in statistics {
+            let age = Duration::from_secs(now_secs.saturating_sub(time).max(0) as u64);
+            let at = now.checked_sub(age).unwrap_or(now);
+            transitions.extend(self.observe_at(&mut state, &batch, at));
+        }
+        transitions
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, EngineState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub async fn observe(&self, batch: &ChangeEventBatch) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        let transitions = self.observe_at(&mut self.lock_state(), batch, Instant::now());
+        self.persist(transitions).await;
+    }
+
+    fn observe_at(
+        &self,
+        state: &mut EngineState,
+        batch: &ChangeEventBatch,
+        at: Instant,
+    ) -> Vec<Transition> {
+        state.last_seen.insert(batch.machine_id, at);
+
+        let mut transitions = vec![];
+        for (metric, series, value) in get_samples(batch) {
+            for (rule_index, rule) in self.rules.iter().enumerate() {
+                if rule.metric != metric {
+                    continue;
+                }
+                let key = AlertKey {
+                    rule: rule_index,
+                    machine_id: batch.machine_id,
+                    series: series.clone(),
+                };
+                match value {
+                    Some(value) => {
+                        let alert_state = state.alerts.entry(key.clone()).or_insert(AlertState {
+                            value,
+                            violated_since: None,
+                            firing: false,
+                        });
+                        alert_state.value = value;
+                        if rule.is_violated(value) {
+                            alert_state.violated_since.get_or_insert(at);
+                        } else {
+                            alert_state.violated_since = None;
+                        }
+                        transitions.extend(self.evaluate(&key, alert_state, at));
+                    }
+                    // the series is gone, e.g. an unmounted disk
+                    None => {
+                        if let Some(mut alert_state) = state.alerts.remove(&key) {
+                            alert_state.violated_since = None;
+                            transitions.extend(self.evaluate(&key, &mut alert_state, at));
+                        }
+                    }
+                }
+            }
+        }
+        transitions
+    }
+
+    /// Fires alerts whose conditions held long enough, e.g. those of
+    /// mounts which are only sent again once they change. A machine
+    /// which stopped reporting doesn't fire any more alerts.
+    pub async fn evaluate_pending(&self) {
+        let transitions: Vec<_> = {
+            let mut state = self.lock_state();
+            let EngineState { alerts, last_seen } = &mut *state;
+            alerts
+                .iter_mut()
+                .filter_map(|(key, alert_state)| {
+                    let last_seen = *last_seen.get(&key.machine_id)?;
+                    self.evaluate(key, alert_state, last_seen)
+                })
+                .collect()
+        };
+
+        self.persist(transitions).await;
+    }
+
+    /// Checks the condition as of `last_seen`, the time of the latest
+    /// values of the machine
+    fn evaluate(
+        &self,
+        key: &AlertKey,
+        state: &mut AlertState,
+        last_seen: Instant,
+    ) -> Option<Transition> {
+        let rule = &self.rules[key.rule];
+        let alert = || Alert {
+            rule: rule.name.clone(),
+            machine_id: key.machine_id,
+            series: key.series.clone(),
+            value: state.value,
+            threshold: rule.threshold,
+        };
+
+        match state.violated_since {
+            Some(since)
+                if !state.firing
+                    && last_seen.saturating_duration_since(since)
+                        >= Duration::from_secs(rule.for_secs) =>
+            {
+                state.firing = true;
+                Some(Transition::Fire(alert()))
+            }
+            None if state.firing => {
+                state.firing = false;
+                Some(Transition::Resolve(alert()))
+            }
+            _ => None,
+        }
+    }
+
+    async fn persist(&self, transitions: Vec<Transition>) {
+        for transition in transitions {
+            match transition {
+                Transition::Fire(alert) => {
+                    warn!(
+                        rule = %alert.rule,
+                        machine_id = alert.machine_id,
+                        series = %alert.series,
+                        value = alert.value,
+                        "Alert fired"
+                    );
+                    if let Err(e) = self.db.open_alert(&alert).await {
+                        error!(error = %e, "Failed to save alert to database");
+                    }
+                    self.notifier.notify(Notification::alert_fired(&alert));
+                }
+                Transition::Resolve(alert) => {
+                    info!(
+                        rule = %alert.rule,
+                        machine_id = alert.machine_id,
+                        series = %alert.series,
+                        "Alert resolved"
+                    );
+                    if let Err(e) = self.db.resolve_alert(&alert).await {
+                        error!(error = %e, "Failed to resolve alert in database");
+                    }
+                    self.notifier.notify(Notification::alert_resolved(&alert));
+                }
+            }
+        }
+    }
+}
+
+fn ratio(used: i64, total: i64) -> f64 {
+    if total <= 0 {
+        return 0.;
+    }
+    used as f64 / total as f64
+}
+
+/// Values of a batch by metric and series, `None` if the series
+/// was deleted
+fn get_samples(batch: &ChangeEventBatch) -> Vec<(AlertMetric, String, Option<f64>)> {
+    let mut samples = vec![];
+    for change_event in &batch.events {
+        let is_delete = change_event.event_type == EventType::Delete as i32;
+        match &change_event.event {
+            Some(Event::Cpu(cpu)) => {
+                samples.push((AlertMetric::CpuUsage, String::new(), Some(cpu.usage.into())));
+                samples.push((AlertMetric::CpuTemp, String::new(), Some(cpu.temp.into())));
+            }
+            Some(Event::Memory(memory)) => {
+                samples.push((
+                    AlertMetric::MemoryUsage,
+                    String::new(),
+                    Some(ratio(memory.total - memory.free, memory.total)),
+                ));
+            }
+            Some(Event::Mount(mount)) => {
+                let (disk_usage, inode_usage) = if is_delete {
+                    (None, None)
+                } else {
+                    (
+                        Some(ratio(mount.total - mount.free, mount.total)),
+                        // files are the used inodes
+                        Some(ratio(mount.files, mount.files + mount.files_avail)),
+                    )
+                };
+                samples.push((
+                    AlertMetric::DiskUsage,
+                    mount.mount_location.clone(),
+                    disk_usage,
+                ));
+                samples.push((
+                    AlertMetric::InodeUsage,
+                    mount.mount_location.clone(),
+                    inode_usage,
+                ));
+            }
+            _ => {}
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_database::TestDatabase;
+    use super::*;
+    use proto::{ChangeEvent, CpuChangeEvent, Mount};
+
+    fn rule(name: &str, metric: AlertMetric, threshold: f64, for_secs: u64) -> AlertRule {
+        AlertRule {
+            name: name.to_string(),
+            metric,
+            comparison: Comparison::Above,
+            threshold,
+            for_secs,
+        }
+    }
+
+    fn cpu_batch(machine_id: i64, usage: f32) -> ChangeEventBatch {
+        ChangeEventBatch {
+            machine_id,
+            events: vec![ChangeEvent {
+                event_type: EventType::Update as i32,
+                event: Some(Event::Cpu(CpuChangeEvent { usage, temp: 40. })),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn mount_event(event_type: EventType, location: &str, used: i64) -> ChangeEvent {
+        ChangeEvent {
+            event_type: event_type as i32,
+            event: Some(Event::Mount(Mount {
+                mount_location: location.to_string(),
+                total: 100,
+                free: 100 - used,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn open_alert(rule: &str, machine_id: i64) -> Alert {
+        Alert {
+            rule: rule.to_string(),
+            machine_id,
+            series: String::new(),
+            value: 1.,
+            threshold: 0.9,
+        }
+    }
+
+    async fn engine(rules: Vec<AlertRule>, db: &Arc<TestDatabase>) -> AlertEngine {
+        AlertEngine::new(rules, db.clone(), Notifier::new(vec![], None, db.clone())).await
+    }
+
+    async fn observe(engine: &AlertEngine, batch: &ChangeEventBatch, at: Instant) {
+        let transitions = engine.observe_at(&mut engine.lock_state(), batch, at);
+        engine.persist(transitions).await;
+    }
+
+    /// Rule, machine and series of each alert
+    fn keys(alerts: &Mutex<Vec<Alert>>) -> Vec<(String, i64, String)> {
+        let mut keys: Vec<_> = alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|alert| (alert.rule.clone(), alert.machine_id, alert.series.clone()))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn key(rule: &str, machine_id: i64, series: &str) -> (String, i64, String) {
+        (rule.to_string(), machine_id, series.to_string())
+    }
+
+    #[tokio::test]
+    async fn fires_once_the_condition_held_long_enough() {
+        let db = Arc::new(TestDatabase::default());
+        let engine = engine(vec![rule("cpu", AlertMetric::CpuUsage, 0.9, 60)], &db).await;
+        let start = Instant::now();
+
+        observe(&engine, &cpu_batch(1, 0.95), start).await;
+        observe(
+            &engine,
+            &cpu_batch(1, 0.97),
+            start + Duration::from_secs(30),
+        )
+        .await;
+        assert!(keys(&db.open_alerts).is_empty());
+
+        observe(
+            &engine,
+            &cpu_batch(1, 0.96),
+            start + Duration::from_secs(60),
+        )
+        .await;
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+        assert_eq!(db.open_alerts.lock().unwrap()[0].value, f64::from(0.96f32));
+
+        // a dip restarts the duration
+        observe(&engine, &cpu_batch(2, 0.95), start).await;
+        observe(&engine, &cpu_batch(2, 0.5), start + Duration::from_secs(50)).await;
+        observe(
+            &engine,
+            &cpu_batch(2, 0.95),
+            start + Duration::from_secs(70),
+        )
+        .await;
+        observe(
+            &engine,
+            &cpu_batch(2, 0.95),
+            start + Duration::from_secs(120),
+        )
+        .await;
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+    }
+
+    #[tokio::test]
+    async fn resolves_once_the_condition_stops() {
+        let db = Arc::new(TestDatabase::default());
+        let engine = engine(vec![rule("cpu", AlertMetric::CpuUsage, 0.9, 0)], &db).await;
+        let start = Instant::now();
+
+        observe(&engine, &cpu_batch(1, 0.95), start).await;
+        observe(&engine, &cpu_batch(1, 0.99), start + Duration::from_secs(5)).await;
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+
+        observe(&engine, &cpu_batch(1, 0.5), start + Duration::from_secs(10)).await;
+        assert!(keys(&db.open_alerts).is_empty());
+        assert_eq!(keys(&db.resolved_alerts), vec![key("cpu", 1, "")]);
+
+        // resolving happens once
+        observe(&engine, &cpu_batch(1, 0.4), start + Duration::from_secs(15)).await;
+        assert_eq!(db.resolved_alerts.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tracks_each_mount_on_its_own() {
+        let db = Arc::new(TestDatabase::default());
+        let engine = engine(vec![rule("disk", AlertMetric::DiskUsage, 0.9, 0)], &db).await;
+        let start = Instant::now();
+
+        let batch = ChangeEventBatch {
+            machine_id: 1,
+            events: vec![
+                mount_event(EventType::Add, "/", 95),
+                mount_event(EventType::Add, "/data", 50),
+                mount_event(EventType::Add, "/backup", 99),
+            ],
+            ..Default::default()
+        };
+        observe(&engine, &batch, start).await;
+        assert_eq!(
+            keys(&db.open_alerts),
+            vec![key("disk", 1, "/"), key("disk", 1, "/backup")]
+        );
+
+        // an unmounted disk resolves its alert
+        let batch = ChangeEventBatch {
+            machine_id: 1,
+            events: vec![mount_event(EventType::Delete, "/backup", 99)],
+            ..Default::default()
+        };
+        observe(&engine, &batch, start + Duration::from_secs(5)).await;
+        assert_eq!(keys(&db.open_alerts), vec![key("disk", 1, "/")]);
+        assert_eq!(keys(&db.resolved_alerts), vec![key("disk", 1, "/backup")]);
+    }
+
+    #[tokio::test]
+    async fn silent_machines_dont_fire() {
+        let db = Arc::new(TestDatabase::default());
+        let engine = engine(vec![rule("disk", AlertMetric::DiskUsage, 0.9, 60)], &db).await;
+
+        // mounts are only sent again once they change
+        let batch = ChangeEventBatch {
+            machine_id: 1,
+            events: vec![mount_event(EventType::Add, "/", 95)],
+            ..Default::default()
+        };
+        // evaluating later only counts up to its last report
+        let start = Instant::now();
+        observe(&engine, &batch, start).await;
+        engine.evaluate_pending().await;
+        assert!(keys(&db.open_alerts).is_empty());
+
+        // reporting again, e.g. its cpu, makes the mount count again
+        observe(&engine, &cpu_batch(1, 0.1), start + Duration::from_secs(60)).await;
+        engine.evaluate_pending().await;
+        assert_eq!(keys(&db.open_alerts), vec![key("disk", 1, "/")]);
+    }
+
+    #[tokio::test]
+    async fn restores_open_alerts() {
+        let db = Arc::new(TestDatabase::default());
+        db.open_alerts.lock().unwrap().push(open_alert("cpu", 1));
+        let engine = engine(vec![rule("cpu", AlertMetric::CpuUsage, 0.9, 60)], &db).await;
+        let start = Instant::now();
+
+        // still firing, thus not opened again
+        observe(&engine, &cpu_batch(1, 0.95), start).await;
+        observe(
+            &engine,
+            &cpu_batch(1, 0.95),
+            start + Duration::from_secs(120),
+        )
+        .await;
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+        assert!(keys(&db.resolved_alerts).is_empty());
+
+        observe(
+            &engine,
+            &cpu_batch(1, 0.5),
+            start + Duration::from_secs(125),
+        )
+        .await;
+        assert!(keys(&db.open_alerts).is_empty());
+        assert_eq!(keys(&db.resolved_alerts), vec![key("cpu", 1, "")]);
+    }
+
+    #[tokio::test]
+    async fn resolves_alerts_of_removed_rules() {
+        let db = Arc::new(TestDatabase::default());
+        db.open_alerts
+            .lock()
+            .unwrap()
+            .extend([open_alert("cpu", 1), open_alert("removed", 1)]);
+        let _engine = engine(vec![rule("cpu", AlertMetric::CpuUsage, 0.9, 60)], &db).await;
+
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+        assert_eq!(keys(&db.resolved_alerts), vec![key("removed", 1, "")]);
+    }
+
+    #[tokio::test]
+    async fn replays_stored_statistics() {
+        let db = Arc::new(TestDatabase::default());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        db.recent_statistics.lock().unwrap().extend([
+            // older than the longest `for`, thus not replayed
+            (now - 600, cpu_batch(3, 0.95)),
+            (now - 55, cpu_batch(1, 0.95)),
+            (now - 5, cpu_batch(1, 0.96)),
+            (now - 5, cpu_batch(2, 0.95)),
+            (now - 600, cpu_batch(3, 0.5)),
+        ]);
+        let engine = engine(vec![rule("cpu", AlertMetric::CpuUsage, 0.9, 60)], &db).await;
+        assert!(keys(&db.open_alerts).is_empty());
+
+        // the condition held since before the restart
+        let next_report = Instant::now() + Duration::from_secs(5);
+        for machine_id in 1..=3 {
+            observe(&engine, &cpu_batch(machine_id, 0.95), next_report).await;
+        }
+        assert_eq!(keys(&db.open_alerts), vec![key("cpu", 1, "")]);
+    }
+}
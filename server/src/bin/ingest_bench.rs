@@ -0,0 +1,188 @@
+//! Measures how many events the server ingests per second by
+//! simulating many machines which send batches at the same time.
+//!
+//! Run the server with `--rate-limit 0` since all simulated machines
+//! share a single token.
+
+use clap::Parser;
+use protocol::event_service_client::EventServiceClient;
+use protocol::{
+    change_event::Event, ChangeEvent, ChangeEventBatch, CpuChangeEvent, CpuInfo, EventType,
+    InitialStateRequest, MemoryChangeEvent, Mount, NetworkDevice, SystemInfo,
+};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tonic::transport::Channel;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct BenchCli {
+    #[clap(short = 'h', long, value_parser, default_value = "http://localhost")]
+    address: String,
+    #[clap(short = 'p', long, value_parser, default_value_t = 50055)]
+    port: u16,
+    /// Simulated machines
+    #[clap(long, value_parser = at_least_one(), default_value_t = 1000)]
+    machines: usize,
+    /// Batches each machine sends
+    #[clap(long, value_parser = at_least_one(), default_value_t = 10)]
+    batches: usize,
+    /// Requests in flight at the same time
+    #[clap(long, value_parser = at_least_one(), default_value_t = 64)]
+    concurrency: usize,
+}
+
+fn at_least_one() -> clap::builder::RangedU64ValueParser<usize> {
+    clap::builder::RangedU64ValueParser::new().range(1..)
+}
+
+const MOUNTS_PER_MACHINE: usize = 4;
+const NETWORK_DEVICES_PER_MACHINE: usize = 2;
+
+/// A batch as a machine typically sends it every interval
+fn make_batch(machine_id: i64, sequence: usize) -> ChangeEventBatch {
+    let mut rng = rand::thread_rng();
+    let change_event = |event_type: EventType, event| ChangeEvent {
+        event_type: event_type.into(),
+        event: Some(event),
+    };
+    // Mounts are new in the first batch and change afterwards
+    let event_type = if sequence == 0 {
+        EventType::Add
+    } else {
+        EventType::Update
+    };
+
+    let mut events = vec![
+        change_event(
+            EventType::Update,
+            Event::Cpu(CpuChangeEvent {
+                usage: rng.gen(),
+                temp: rng.gen_range(30., 90.),
+            }),
+        ),
+        change_event(
+            EventType::Update,
+            Event::Memory(MemoryChangeEvent {
+                total: 16 << 30,
+                free: rng.gen_range(0, 16 << 30),
+            }),
+        ),
+    ];
+    events.extend((0..MOUNTS_PER_MACHINE).map(|i| {
+        change_event(
+            event_type,
+            Event::Mount(Mount {
+                device_name: format!("/dev/sda{}", i),
+                mount_location: format!("/mnt/{}", i),
+                total: 1 << 40,
+                free: rng.gen_range(0, 1 << 40),
+                fs_type: "ext4".to_string(),
+                files: 1 << 20,
+                files_avail: rng.gen_range(0, 1 << 20),
+            }),
+        )
+    }));
+    events.extend((0..NETWORK_DEVICES_PER_MACHINE).map(|i| {
+        change_event(
+            event_type,
+            Event::NetworkDevice(NetworkDevice {
+                name: format!("eth{}", i),
+                bytes_received: rng.gen_range(0, i64::MAX),
+                bytes_sent: rng.gen_range(0, i64::MAX),
+            }),
+        )
+    }));
+
+    ChangeEventBatch {
+        events,
+        machine_id,
+        ..Default::default()
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = BenchCli::parse();
+    let channel = Channel::from_shared(format!("{}:{}", cli.address, cli.port))?
+        .connect()
+        .await?;
+    let client = EventServiceClient::new(channel);
+
+    // Random ids so that runs don't collide with each other
+    let first_machine_id = rand::thread_rng().gen_range(1, i64::MAX / 2);
+    let machine_ids: Vec<i64> = (0..cli.machines as i64)
+        .map(|i| first_machine_id + i)
+        .collect();
+
+    println!("Registering {} machines", cli.machines);
+    for &machine_id in &machine_ids {
+        client
+            .clone()
+            .initial_state(InitialStateRequest {
+                machine_id,
+                system_info: Some(SystemInfo {
+                    boot_time: Some(prost_types::Timestamp::default()),
+                    hostname: format!("bench-{}", machine_id),
+                }),
+                cpu_info: Some(CpuInfo { n_cores: 8 }),
+                report_interval: 5,
+            })
+            .await?;
+    }
+
+    println!(
+        "Sending {} batches per machine with {} requests in flight",
+        cli.batches, cli.concurrency
+    );
+    let semaphore = Arc::new(Semaphore::new(cli.concurrency));
+    let start = Instant::now();
+    let mut handles = vec![];
+    let mut n_events = 0;
+
+    for sequence in 0..cli.batches {
+        for &machine_id in &machine_ids {
+            let batch = make_batch(machine_id, sequence);
+            n_events += batch.events.len();
+
+            // unwrapping is safe as the semaphore is never closed
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let mut client = client.clone();
+            handles.push(tokio::task::spawn(async move {
+                let started = Instant::now();
+                let result = client.send_events(batch).await;
+                drop(permit);
+                result.map(|_| started.elapsed())
+            }));
+        }
+    }
+
+    let mut latencies = Vec::with_capacity(handles.len());
+    for handle in handles {
+        latencies.push(handle.await??);
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    let n_batches = latencies.len();
+    let percentile = |p: usize| latencies[(n_batches * p / 100).min(n_batches - 1)];
+    println!(
+        "Sent {} batches with {} events in {:?}",
+        n_batches, n_events, elapsed
+    );
+    println!(
+        "{:.0} batches/s, {:.0} events/s",
+        n_batches as f64 / elapsed.as_secs_f64(),
+        n_events as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Latency p50 {:?}, p99 {:?}, max {:?}",
+        percentile(50),
+        percentile(99),
+        latencies.last().copied().unwrap_or(Duration::ZERO)
+    );
+
+    Ok(())
+}
@@ -1,21 +1,506 @@
 extern crate protocol as proto;
 
 use self::proto::{
-    change_event::Event, ChangeEventBatch, CpuInfo, EventType, Mount, NetworkDevice, SystemInfo,
+    change_event::Event, Cgroup, ChangeEvent, ChangeEventBatch, CpuChangeEvent, CpuInfo, CpuSample,
+    EventType, MachineInfo, MachineStatus, MemoryChangeEvent, MemorySample, MetricKind, Mount,
+    NetworkDevice, ProbeResult, Process, SystemInfo,
 };
+use super::retention::{Resolution, RetentionPolicy};
 use async_trait::async_trait;
 use sqlx::error::Error;
 use sqlx::pool::Pool;
 use sqlx::postgres::{PgPoolOptions, Postgres};
+use sqlx::Row;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Debug;
+use tracing::{debug, error, info, trace};
+
+/// A machine known to the server
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub id: i64,
+    pub ip: Option<String>,
+    pub hostname: String,
+    // seconds since epoch
+    pub boot_time: i64,
+    // sha256 of the auth token in hex
+    pub token_hash: String,
+    // seconds between two reports
+    pub report_interval: i32,
+}
+
+/// A machine which did not report in time anymore
+#[derive(Debug, Clone)]
+pub struct SilentMachine {
+    pub id: i64,
+    pub previous_status: MachineStatus,
+    pub status: MachineStatus,
+    pub silent_secs: f64,
+    pub report_interval: i32,
+}
+
+fn machine_status_from_str(status: &str) -> MachineStatus {
+    match status {
+        "stale" => MachineStatus::Stale,
+        "offline" => MachineStatus::Offline,
+        _ => MachineStatus::Online,
+    }
+}
+
+/// Seconds the reported boot time may jitter, clients derive it from
+/// the uptime
+const BOOT_TIME_TOLERANCE: i64 = 5;
+
+impl Machine {
+    /// Checks whether `other` claiming the same machine id is
+    /// plausibly the same host, e.g. after a reboot or a rename. A host
+    /// with another auth token never is, as it would take over the
+    /// machine together with its data.
+    pub fn is_same_host(&self, other: &Machine) -> bool {
+        if self.token_hash != other.token_hash {
+            return false;
+        }
+        // A machine cannot boot back in time
+        if other.boot_time + BOOT_TIME_TOLERANCE < self.boot_time {
+            return false;
+        }
+        // Renamed without a reboot
+        if other.boot_time - self.boot_time <= BOOT_TIME_TOLERANCE {
+            return true;
+        }
+        // After a reboot either the name or the address stays
+        self.hostname == other.hostname || (self.ip.is_some() && self.ip == other.ip)
+    }
+}
+
+/// A condition of an alert rule which held long enough
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub rule: String,
+    pub machine_id: i64,
+    // e.g. the mount location, empty for machine wide rules
+    pub series: String,
+    pub value: f64,
+    pub threshold: f64,
+}
 
 #[async_trait]
 pub trait Database: Sync + Send + Debug {
-    async fn process_event(&self, event_batch: &ChangeEventBatch);
+    /// Writes the events of many batches with a few bulk queries
+    async fn process_events(&self, event_batches: &[ChangeEventBatch]) -> Result<(), Error>;
     async fn save_system_info(&self, machine_id: i64, system_info: &SystemInfo);
     async fn save_cpu_info(&self, machine_id: i64, cpu_info: &CpuInfo);
     async fn fetch_mounts(&self, machine_id: i64) -> Result<Vec<Mount>, Error>;
     async fn fetch_network_devices(&self, machine_id: i64) -> Result<Vec<NetworkDevice>, Error>;
+    async fn fetch_processes(&self, machine_id: i64) -> Result<Vec<Process>, Error>;
+    async fn fetch_cgroups(&self, machine_id: i64) -> Result<Vec<Cgroup>, Error>;
+    /// Latest result of every probe of the machine which is not removed
+    async fn fetch_probe_results(&self, machine_id: i64) -> Result<Vec<ProbeResult>, Error>;
+    async fn fetch_machine(&self, machine_id: i64) -> Result<Option<Machine>, Error>;
+    /// Returns false if the machine id is taken already
+    async fn insert_machine(&self, machine: &Machine) -> Result<bool, Error>;
+    /// Returns false if the machine changed since it was fetched as
+    /// `registered`. The token a machine registered with is kept.
+    async fn update_machine(&self, machine: &Machine, registered: &Machine) -> Result<bool, Error>;
+    /// Machines registered with the token of the given hash
+    async fn fetch_machines(&self, token_hash: &str) -> Result<Vec<MachineInfo>, Error>;
+    /// Returns the machines which were not online before together
+    /// with their previous status
+    async fn mark_machines_seen(
+        &self,
+        machine_ids: &[i64],
+    ) -> Result<Vec<(i64, MachineStatus)>, Error>;
+    /// Marks machines which missed `stale_after` or `offline_after`
+    /// times their report interval and returns those whose status
+    /// changed
+    async fn sweep_machine_status(
+        &self,
+        stale_after: f64,
+        offline_after: f64,
+    ) -> Result<Vec<SilentMachine>, Error>;
+    async fn fetch_open_alerts(&self) -> Result<Vec<Alert>, Error>;
+    async fn open_alert(&self, alert: &Alert) -> Result<(), Error>;
+    async fn resolve_alert(&self, alert: &Alert) -> Result<(), Error>;
+    async fn save_dead_letter(
+        &self,
+        url: &str,
+        payload: &str,
+        error: &str,
+        attempts: i32,
+    ) -> Result<(), Error>;
+    /// Aggregates raw statistics into per minute and per hour ones
+    async fn roll_up_statistics(&self) -> Result<(), Error>;
+    /// Returns the number of deleted rows, plus the dropped chunks of
+    /// tables which are TimescaleDB hypertables
+    async fn delete_expired_statistics(&self, retention: &RetentionPolicy) -> Result<u64, Error>;
+    async fn fetch_cpu_statistics(
+        &self,
+        machine_id: i64,
+        from: i64,
+        to: i64,
+        resolution: Resolution,
+    ) -> Result<Vec<CpuSample>, Error>;
+    async fn fetch_memory_statistics(
+        &self,
+        machine_id: i64,
+        from: i64,
+        to: i64,
+        resolution: Resolution,
+    ) -> Result<Vec<MemorySample>, Error>;
+    /// Raw cpu and memory statistics of all machines since `since` as
+    /// single event batches with their time, oldest first
+    async fn fetch_recent_statistics(
+        &self,
+        since: i64,
+    ) -> Result<Vec<(i64, ChangeEventBatch)>, Error>;
+    async fn check_health(&self) -> Result<(), Error>;
+}
+
+/// Rollup queries in the order they need to run. Each one starts at
+/// the last bucket it wrote since that one may have been incomplete.
+const ROLLUP_QUERIES: [&str; 4] = [
+    "
+    INSERT INTO cpu_statistics_1m (
+        machine_id, bucket, usage_avg, usage_max, temperature_avg, temperature_max, n_samples
+    )
+    SELECT machine_id, date_trunc('minute', created_at), AVG(usage), MAX(usage),
+           AVG(temperature), MAX(temperature), COUNT(*)
+        FROM cpu_statistics
+        WHERE created_at >= (
+            SELECT COALESCE(MAX(bucket), '-infinity') FROM cpu_statistics_1m
+        )
+        GROUP BY 1, 2
+    ON CONFLICT (machine_id, bucket) DO UPDATE SET
+        usage_avg = EXCLUDED.usage_avg,
+        usage_max = EXCLUDED.usage_max,
+        temperature_avg = EXCLUDED.temperature_avg,
+        temperature_max = EXCLUDED.temperature_max,
+        n_samples = EXCLUDED.n_samples
+    ",
+    "
+    INSERT INTO cpu_statistics_1h (
+        machine_id, bucket, usage_avg, usage_max, temperature_avg, temperature_max, n_samples
+    )
+    SELECT machine_id, date_trunc('hour', bucket),
+           SUM(usage_avg * n_samples) / SUM(n_samples), MAX(usage_max),
+           SUM(temperature_avg * n_samples) / SUM(n_samples), MAX(temperature_max),
+           SUM(n_samples)
+        FROM cpu_statistics_1m
+        WHERE bucket >= (
+            SELECT COALESCE(MAX(bucket), '-infinity') FROM cpu_statistics_1h
+        )
+        GROUP BY 1, 2
+    ON CONFLICT (machine_id, bucket) DO UPDATE SET
+        usage_avg = EXCLUDED.usage_avg,
+        usage_max = EXCLUDED.usage_max,
+        temperature_avg = EXCLUDED.temperature_avg,
+        temperature_max = EXCLUDED.temperature_max,
+        n_samples = EXCLUDED.n_samples
+    ",
+    "
+    INSERT INTO memory_statistics_1m (machine_id, bucket, total, free_avg, free_min, n_samples)
+    SELECT machine_id, date_trunc('minute', created_at), MAX(total), AVG(free)::BIGINT,
+           MIN(free), COUNT(*)
+        FROM memory_statistics
+        WHERE machine_id IS NOT NULL AND created_at >= (
+            SELECT COALESCE(MAX(bucket), '-infinity') FROM memory_statistics_1m
+        )
+        GROUP BY 1, 2
+    ON CONFLICT (machine_id, bucket) DO UPDATE SET
+        total = EXCLUDED.total,
+        free_avg = EXCLUDED.free_avg,
+        free_min = EXCLUDED.free_min,
+        n_samples = EXCLUDED.n_samples
+    ",
+    "
+    INSERT INTO memory_statistics_1h (machine_id, bucket, total, free_avg, free_min, n_samples)
+    SELECT machine_id, date_trunc('hour', bucket), MAX(total),
+           (SUM(free_avg * n_samples) / SUM(n_samples))::BIGINT, MIN(free_min), SUM(n_samples)
+        FROM memory_statistics_1m
+        WHERE bucket >= (
+            SELECT COALESCE(MAX(bucket), '-infinity') FROM memory_statistics_1h
+        )
+        GROUP BY 1, 2
+    ON CONFLICT (machine_id, bucket) DO UPDATE SET
+        total = EXCLUDED.total,
+        free_avg = EXCLUDED.free_avg,
+        free_min = EXCLUDED.free_min,
+        n_samples = EXCLUDED.n_samples
+    ",
+];
+
+/// Rows of a table as one array per column, bound as a whole to an
+/// `UNNEST` query
+#[derive(Debug, Default)]
+struct CpuColumns {
+    machine_ids: Vec<i64>,
+    usage: Vec<f32>,
+    temp: Vec<f32>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryColumns {
+    machine_ids: Vec<i64>,
+    total: Vec<i64>,
+    free: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+struct MountColumns {
+    machine_ids: Vec<i64>,
+    device_name: Vec<String>,
+    mount_location: Vec<String>,
+    total: Vec<i64>,
+    free: Vec<i64>,
+    fs_type: Vec<String>,
+    files: Vec<i64>,
+    files_avail: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+struct NetworkDeviceColumns {
+    machine_ids: Vec<i64>,
+    name: Vec<String>,
+    bytes_received: Vec<i64>,
+    bytes_sent: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+struct ProcessColumns {
+    machine_ids: Vec<i64>,
+    name: Vec<String>,
+    // array literals such as "{1,2}"
+    pids: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct ProcessTransitionColumns {
+    machine_ids: Vec<i64>,
+    name: Vec<String>,
+    transition: Vec<String>,
+    pids: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct CgroupColumns {
+    machine_ids: Vec<i64>,
+    path: Vec<String>,
+    cpu_usage_usec: Vec<i64>,
+    memory_current: Vec<i64>,
+    memory_max: Vec<i64>,
+    io_read_bytes: Vec<i64>,
+    io_write_bytes: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+struct MetricColumns {
+    machine_ids: Vec<i64>,
+    name: Vec<String>,
+    // JSON objects such as {"status": "500"}
+    labels: Vec<String>,
+    value: Vec<f64>,
+    unit: Vec<String>,
+    kind: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct ProbeResultColumns {
+    machine_ids: Vec<i64>,
+    name: Vec<String>,
+    target: Vec<String>,
+    success: Vec<bool>,
+    latency_ms: Vec<f64>,
+    status_code: Vec<i32>,
+    error: Vec<String>,
+    removed: Vec<bool>,
+}
+
+/// Identifies deleted rows of the state tables
+#[derive(Debug, Default)]
+struct KeyColumns {
+    machine_ids: Vec<i64>,
+    keys: Vec<String>,
+}
+
+impl KeyColumns {
+    fn push(&mut self, machine_id: i64, key: &str) {
+        self.machine_ids.push(machine_id);
+        self.keys.push(key.to_string());
+    }
+}
+
+fn to_array_literal(values: &[i64]) -> String {
+    let values: Vec<_> = values.iter().map(|value| value.to_string()).collect();
+    format!("{{{}}}", values.join(","))
+}
+
+#[derive(Debug, Default)]
+struct EventColumns {
+    cpu: CpuColumns,
+    memory: MemoryColumns,
+    mounts: MountColumns,
+    deleted_mounts: KeyColumns,
+    network_devices: NetworkDeviceColumns,
+    deleted_network_devices: KeyColumns,
+    processes: ProcessColumns,
+    deleted_processes: KeyColumns,
+    process_transitions: ProcessTransitionColumns,
+    cgroups: CgroupColumns,
+    deleted_cgroups: KeyColumns,
+    metrics: MetricColumns,
+    probe_results: ProbeResultColumns,
+}
+
+impl EventColumns {
+    fn new(event_batches: &[ChangeEventBatch]) -> Self {
+        let mut columns = EventColumns::default();
+
+        // A row of a state table may change several times within the
+        // batches, only the last change counts. Postgres refuses to
+        // upsert the same row twice in one statement anyway. Rows are
+        // written in key order, so that concurrent writes lock them in
+        // the same order and don't deadlock.
+        let mut mounts = BTreeMap::new();
+        let mut network_devices = BTreeMap::new();
+        let mut processes = BTreeMap::new();
+        let mut cgroups = BTreeMap::new();
+
+        for event_batch in event_batches {
+            let machine_id = event_batch.machine_id;
+            for change_event in &event_batch.events {
+                let event_type = change_event.event_type();
+                match &change_event.event {
+                    Some(Event::Cpu(cpu)) => {
+                        columns.cpu.machine_ids.push(machine_id);
+                        columns.cpu.usage.push(cpu.usage);
+                        columns.cpu.temp.push(cpu.temp);
+                    }
+                    Some(Event::Memory(memory)) => {
+                        columns.memory.machine_ids.push(machine_id);
+                        columns.memory.total.push(memory.total);
+                        columns.memory.free.push(memory.free);
+                    }
+                    Some(Event::Mount(mount)) => {
+                        mounts.insert((machine_id, &mount.device_name), (event_type, mount));
+                    }
+                    Some(Event::NetworkDevice(network_device)) => {
+                        network_devices.insert(
+                            (machine_id, &network_device.name),
+                            (event_type, network_device),
+                        );
+                    }
+                    Some(Event::Process(process)) => {
+                        processes.insert((machine_id, &process.name), (event_type, process));
+
+                        // The history keeps every transition
+                        let transitions = &mut columns.process_transitions;
+                        transitions.machine_ids.push(machine_id);
+                        transitions.name.push(process.name.clone());
+                        transitions.transition.push(
+                            match event_type {
+                                EventType::Add => "ADD",
+                                EventType::Update => "UPDATE",
+                                EventType::Delete => "DELETE",
+                            }
+                            .to_string(),
+                        );
+                        transitions.pids.push(to_array_literal(&process.pids));
+                    }
+                    Some(Event::Cgroup(cgroup)) => {
+                        cgroups.insert((machine_id, &cgroup.path), (event_type, cgroup));
+                    }
+                    // Results are kept as history, a removed probe gets a
+                    // last row marking it as such
+                    Some(Event::ProbeResult(probe_result)) => {
+                        let rows = &mut columns.probe_results;
+                        rows.machine_ids.push(machine_id);
+                        rows.name.push(probe_result.name.clone());
+                        rows.target.push(probe_result.target.clone());
+                        rows.success.push(probe_result.success);
+                        rows.latency_ms.push(probe_result.latency_ms);
+                        rows.status_code.push(probe_result.status_code);
+                        rows.error.push(probe_result.error.clone());
+                        rows.removed.push(event_type == EventType::Delete);
+                    }
+                    // Metrics are samples, the event type doesn't matter
+                    Some(Event::Metric(metric)) => {
+                        let rows = &mut columns.metrics;
+                        rows.machine_ids.push(machine_id);
+                        rows.name.push(metric.name.clone());
+                        // unwrapping is safe as string maps always serialize
+                        rows.labels
+                            .push(serde_json::to_string(&metric.labels).unwrap());
+                        rows.value.push(metric.value);
+                        rows.unit.push(metric.unit.clone());
+                        rows.kind.push(
+                            match metric.kind() {
+                                MetricKind::Gauge => "GAUGE",
+                                MetricKind::Counter => "COUNTER",
+                            }
+                            .to_string(),
+                        );
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        for ((machine_id, device_name), (event_type, mount)) in mounts {
+            if event_type == EventType::Delete {
+                columns.deleted_mounts.push(machine_id, device_name);
+                continue;
+            }
+            let rows = &mut columns.mounts;
+            rows.machine_ids.push(machine_id);
+            rows.device_name.push(mount.device_name.clone());
+            rows.mount_location.push(mount.mount_location.clone());
+            rows.total.push(mount.total);
+            rows.free.push(mount.free);
+            rows.fs_type.push(mount.fs_type.clone());
+            rows.files.push(mount.files);
+            rows.files_avail.push(mount.files_avail);
+        }
+
+        for ((machine_id, name), (event_type, network_device)) in network_devices {
+            if event_type == EventType::Delete {
+                columns.deleted_network_devices.push(machine_id, name);
+                continue;
+            }
+            let rows = &mut columns.network_devices;
+            rows.machine_ids.push(machine_id);
+            rows.name.push(network_device.name.clone());
+            rows.bytes_received.push(network_device.bytes_received);
+            rows.bytes_sent.push(network_device.bytes_sent);
+        }
+
+        for ((machine_id, name), (event_type, process)) in processes {
+            if event_type == EventType::Delete {
+                columns.deleted_processes.push(machine_id, name);
+                continue;
+            }
+            let rows = &mut columns.processes;
+            rows.machine_ids.push(machine_id);
+            rows.name.push(process.name.clone());
+            rows.pids.push(to_array_literal(&process.pids));
+        }
+
+        for ((machine_id, path), (event_type, cgroup)) in cgroups {
+            if event_type == EventType::Delete {
+                columns.deleted_cgroups.push(machine_id, path);
+                continue;
+            }
+            let rows = &mut columns.cgroups;
+            rows.machine_ids.push(machine_id);
+            rows.path.push(cgroup.path.clone());
+            rows.cpu_usage_usec.push(cgroup.cpu_usage_usec);
+            rows.memory_current.push(cgroup.memory_current);
+            rows.memory_max.push(cgroup.memory_max);
+            rows.io_read_bytes.push(cgroup.io_read_bytes);
+            rows.io_write_bytes.push(cgroup.io_write_bytes);
+        }
+
+        columns
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,125 +515,299 @@ impl PgDatabase {
             .connect(db_uri)
             .await
             .expect("Failed to connect to database");
+        info!("Connected to database");
 
         PgDatabase { pool }
     }
+
+    /// Tables which are TimescaleDB hypertables. The migrations only
+    /// create them if TimescaleDB was installed at the time, thus this
+    /// is checked per table.
+    async fn fetch_hypertables(&self) -> Result<HashSet<String>, Error> {
+        let installed: bool = sqlx::query(
+            "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') AS installed",
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .try_get("installed")?;
+        if !installed {
+            return Ok(HashSet::new());
+        }
+
+        sqlx::query(
+            "
+        SELECT hypertable_name
+            FROM timescaledb_information.hypertables
+            WHERE hypertable_schema = current_schema()
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| row.try_get("hypertable_name"))
+        .collect()
+    }
 }
 
 #[async_trait]
 impl Database for PgDatabase {
-    async fn process_event(&self, event_batch: &ChangeEventBatch) {
-        for event in event_batch.events.iter() {
-            eprintln!("Got event: {:?}", event);
-
-            let event_type = event.event_type();
-            let query = match &event.event {
-                Some(event) => match event {
-                    // CPU Statistics
-                    Event::Cpu(cpu) => sqlx::query(
-                        "
-                        INSERT INTO cpu_statistics (machine_id, usage, temperature)
-                            VALUES ($1, $2, $3)
-                        ",
+    async fn process_events(&self, event_batches: &[ChangeEventBatch]) -> Result<(), Error> {
+        let columns = EventColumns::new(event_batches);
+        trace!(?columns, "Writing events");
+
+        let queries = [
+            // CPU Statistics
+            (
+                columns.cpu.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO cpu_statistics (machine_id, usage, temperature)
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::REAL[], $3::REAL[])
+                ",
+                )
+                .bind(columns.cpu.machine_ids)
+                .bind(columns.cpu.usage)
+                .bind(columns.cpu.temp),
+            ),
+            // RAM Statistics
+            (
+                columns.memory.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO memory_statistics (machine_id, total, free)
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::BIGINT[], $3::BIGINT[])
+                ",
+                )
+                .bind(columns.memory.machine_ids)
+                .bind(columns.memory.total)
+                .bind(columns.memory.free),
+            ),
+            // Mounts
+            (
+                columns.mounts.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO mounts (
+                    machine_id, device_name, mount_location,
+                    total, free, fs_type, files, files_avail
+                )
+                    SELECT * FROM UNNEST(
+                        $1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::BIGINT[],
+                        $5::BIGINT[], $6::TEXT[], $7::BIGINT[], $8::BIGINT[]
+                    )
+                    ON CONFLICT (machine_id, device_name) DO UPDATE SET
+                        mount_location = EXCLUDED.mount_location,
+                        total = EXCLUDED.total,
+                        free = EXCLUDED.free,
+                        fs_type = EXCLUDED.fs_type,
+                        files = EXCLUDED.files,
+                        files_avail = EXCLUDED.files_avail
+                ",
+                )
+                .bind(columns.mounts.machine_ids)
+                .bind(columns.mounts.device_name)
+                .bind(columns.mounts.mount_location)
+                .bind(columns.mounts.total)
+                .bind(columns.mounts.free)
+                .bind(columns.mounts.fs_type)
+                .bind(columns.mounts.files)
+                .bind(columns.mounts.files_avail),
+            ),
+            (
+                columns.deleted_mounts.machine_ids.len(),
+                sqlx::query(
+                    "
+                DELETE FROM mounts WHERE (machine_id, device_name) IN (
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[])
+                )
+                ",
+                )
+                .bind(columns.deleted_mounts.machine_ids)
+                .bind(columns.deleted_mounts.keys),
+            ),
+            // Network Devices
+            (
+                columns.network_devices.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO network_device_statistics (
+                    machine_id, device_name, bytes_received, bytes_sent
+                )
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::BIGINT[], $4::BIGINT[])
+                    ON CONFLICT (machine_id, device_name) DO UPDATE SET
+                        bytes_received = EXCLUDED.bytes_received,
+                        bytes_sent = EXCLUDED.bytes_sent
+                ",
+                )
+                .bind(columns.network_devices.machine_ids)
+                .bind(columns.network_devices.name)
+                .bind(columns.network_devices.bytes_received)
+                .bind(columns.network_devices.bytes_sent),
+            ),
+            (
+                columns.deleted_network_devices.machine_ids.len(),
+                sqlx::query(
+                    "
+                DELETE FROM network_device_statistics WHERE (machine_id, device_name) IN (
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[])
+                )
+                ",
+                )
+                .bind(columns.deleted_network_devices.machine_ids)
+                .bind(columns.deleted_network_devices.keys),
+            ),
+            // Processes
+            // Arrays of arrays can't be unnested row by row, thus the
+            // pids are passed as array literals.
+            (
+                columns.processes.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO processes (machine_id, name, pids)
+                    SELECT machine_id, name, pids::BIGINT[]
+                        FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::TEXT[])
+                            AS p(machine_id, name, pids)
+                    ON CONFLICT (machine_id, name) DO UPDATE SET
+                        pids = EXCLUDED.pids
+                ",
+                )
+                .bind(columns.processes.machine_ids)
+                .bind(columns.processes.name)
+                .bind(columns.processes.pids),
+            ),
+            (
+                columns.deleted_processes.machine_ids.len(),
+                sqlx::query(
+                    "
+                DELETE FROM processes WHERE (machine_id, name) IN (
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[])
+                )
+                ",
+                )
+                .bind(columns.deleted_processes.machine_ids)
+                .bind(columns.deleted_processes.keys),
+            ),
+            (
+                columns.process_transitions.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO process_transitions (machine_id, name, transition, pids)
+                    SELECT machine_id, name, transition, pids::BIGINT[]
+                        FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::TEXT[])
+                            AS t(machine_id, name, transition, pids)
+                ",
+                )
+                .bind(columns.process_transitions.machine_ids)
+                .bind(columns.process_transitions.name)
+                .bind(columns.process_transitions.transition)
+                .bind(columns.process_transitions.pids),
+            ),
+            // Cgroups
+            (
+                columns.cgroups.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO cgroup_statistics (
+                    machine_id, path, cpu_usage_usec, memory_current, memory_max,
+                    io_read_bytes, io_write_bytes
+                )
+                    SELECT * FROM UNNEST(
+                        $1::BIGINT[], $2::TEXT[], $3::BIGINT[], $4::BIGINT[],
+                        $5::BIGINT[], $6::BIGINT[], $7::BIGINT[]
                     )
-                    .bind(event_batch.machine_id)
-                    .bind(cpu.usage)
-                    .bind(cpu.temp),
-                    // RAM Statistics
-                    Event::Memory(mem) => sqlx::query(
-                        "
-                        INSERT INTO memory_statistics (machine_id, total, free)
-                            VALUES ($1, $2, $3)
-                            ",
+                    ON CONFLICT (machine_id, path) DO UPDATE SET
+                        cpu_usage_usec = EXCLUDED.cpu_usage_usec,
+                        memory_current = EXCLUDED.memory_current,
+                        memory_max = EXCLUDED.memory_max,
+                        io_read_bytes = EXCLUDED.io_read_bytes,
+                        io_write_bytes = EXCLUDED.io_write_bytes
+                ",
+                )
+                .bind(columns.cgroups.machine_ids)
+                .bind(columns.cgroups.path)
+                .bind(columns.cgroups.cpu_usage_usec)
+                .bind(columns.cgroups.memory_current)
+                .bind(columns.cgroups.memory_max)
+                .bind(columns.cgroups.io_read_bytes)
+                .bind(columns.cgroups.io_write_bytes),
+            ),
+            (
+                columns.deleted_cgroups.machine_ids.len(),
+                sqlx::query(
+                    "
+                DELETE FROM cgroup_statistics WHERE (machine_id, path) IN (
+                    SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[])
+                )
+                ",
+                )
+                .bind(columns.deleted_cgroups.machine_ids)
+                .bind(columns.deleted_cgroups.keys),
+            ),
+            // Metrics
+            (
+                columns.metrics.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO metric_samples (machine_id, name, labels, value, unit, kind)
+                    SELECT machine_id, name, labels::JSONB, value, unit, kind
+                        FROM UNNEST(
+                            $1::BIGINT[], $2::TEXT[], $3::TEXT[],
+                            $4::DOUBLE PRECISION[], $5::TEXT[], $6::TEXT[]
+                        ) AS m(machine_id, name, labels, value, unit, kind)
+                ",
+                )
+                .bind(columns.metrics.machine_ids)
+                .bind(columns.metrics.name)
+                .bind(columns.metrics.labels)
+                .bind(columns.metrics.value)
+                .bind(columns.metrics.unit)
+                .bind(columns.metrics.kind),
+            ),
+            // Probes
+            (
+                columns.probe_results.machine_ids.len(),
+                sqlx::query(
+                    "
+                INSERT INTO probe_results (
+                    machine_id, name, target, success, latency_ms, status_code, error, removed
+                )
+                    SELECT * FROM UNNEST(
+                        $1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::BOOLEAN[],
+                        $5::DOUBLE PRECISION[], $6::INTEGER[], $7::TEXT[], $8::BOOLEAN[]
                     )
-                    .bind(event_batch.machine_id)
-                    .bind(mem.total)
-                    .bind(mem.free),
-                    // Mounts
-                    Event::Mount(mount) => match event_type {
-                        EventType::Add | EventType::Update => sqlx::query(
-                            "
-                                INSERT INTO mounts (
-                                    machine_id, device_name, mount_location,
-                                    total, free, fs_type    
-                                ) 
-                                VALUES ($1, $2, $3, $4, $5, $6)
-                                ON CONFLICT (machine_id, device_name) DO UPDATE SET
-                                    mount_location = $3,
-                                    total = $4,
-                                    free = $5,
-                                    fs_type = $6
-                                    ",
-                        )
-                        .bind(event_batch.machine_id)
-                        .bind(mount.device_name.to_string())
-                        .bind(mount.mount_location.to_string())
-                        .bind(mount.total)
-                        .bind(mount.free)
-                        .bind(mount.fs_type.to_string()),
-
-                        EventType::Delete => sqlx::query(
-                            "
-                                DELETE FROM mounts WHERE
-                                    machine_id = $1 AND
-                                    device_name = $2
-                                ",
-                        )
-                        .bind(event_batch.machine_id)
-                        .bind(mount.device_name.to_string()),
-                    },
-                    // Network Devices
-                    Event::NetworkDevice(net_device) => match event_type {
-                        EventType::Add | EventType::Update => sqlx::query(
-                            "
-                                INSERT INTO network_device_statistics (
-                                    machine_id, device_name, 
-                                    bytes_received, bytes_sent
-                                ) 
-                                VALUES ($1, $2, $3, $4)
-                                ON CONFLICT (machine_id, device_name) DO UPDATE SET
-                                    device_name = $2,
-                                    bytes_received = $3,
-                                    bytes_sent = $4
-                                    ",
-                        )
-                        .bind(event_batch.machine_id)
-                        .bind(&net_device.name)
-                        .bind(net_device.bytes_received)
-                        .bind(net_device.bytes_sent),
-
-                        EventType::Delete => sqlx::query(
-                            "
-                                DELETE FROM network_device_statistics WHERE
-                                    machine_id = $1 AND
-                                    device_name = $2
-                                ",
-                        )
-                        .bind(event_batch.machine_id)
-                        .bind(&net_device.name),
-                    },
-                },
-                None => {
-                    // null query
-                    sqlx::query("")
-                }
-            };
+                ",
+                )
+                .bind(columns.probe_results.machine_ids)
+                .bind(columns.probe_results.name)
+                .bind(columns.probe_results.target)
+                .bind(columns.probe_results.success)
+                .bind(columns.probe_results.latency_ms)
+                .bind(columns.probe_results.status_code)
+                .bind(columns.probe_results.error)
+                .bind(columns.probe_results.removed),
+            ),
+        ];
 
-            match query.execute(&self.pool).await {
-                Ok(_) => eprintln!("Updated database"),
-                Err(err) => {
-                    eprintln!("Failed to update database: {}", err);
-                }
-            };
+        // A single transaction saves flushing to disk for every query
+        let mut transaction = self.pool.begin().await?;
+
+        for (n_rows, query) in queries {
+            // Skip the round trip if there is nothing to write
+            if n_rows == 0 {
+                continue;
+            }
+            query.execute(&mut transaction).await?;
+            trace!(n_rows, "Updated database");
         }
+
+        transaction.commit().await
     }
 
     async fn save_system_info(&self, machine_id: i64, system_info: &SystemInfo) {
         let mut boot_time: i64 = 0;
-        match &system_info.boot_time {
-            Some(boot_time_) => boot_time = boot_time_.seconds,
-            None => {}
-        };
+        if let Some(boot_time_) = &system_info.boot_time {
+            boot_time = boot_time_.seconds;
+        }
 
         match sqlx::query(
             "
@@ -163,9 +822,9 @@ impl Database for PgDatabase {
         .execute(&self.pool)
         .await
         {
-            Ok(_) => eprintln!("Inserted system info"),
+            Ok(_) => debug!("Inserted system info"),
             Err(err) => {
-                eprintln!("Failed to insert system info event: {}", err);
+                error!(error = %err, "Failed to insert system info event");
             }
         }
     }
@@ -184,9 +843,9 @@ impl Database for PgDatabase {
         .execute(&self.pool)
         .await
         {
-            Ok(_) => eprintln!("Updated database"),
+            Ok(_) => debug!("Inserted cpu info"),
             Err(err) => {
-                eprintln!("Failed to update database: {}", err);
+                error!(error = %err, "Failed to insert cpu info");
             }
         }
     }
@@ -194,7 +853,8 @@ impl Database for PgDatabase {
     async fn fetch_mounts(&self, machine_id: i64) -> Result<Vec<Mount>, Error> {
         sqlx::query_as::<_, Mount>(
             "
-        SELECT device_name, mount_location, total, free, fs_type
+        SELECT device_name, mount_location, total, free, fs_type,
+               files, files_avail
             FROM mounts
             WHERE machine_id = $1
             ",
@@ -216,4 +876,595 @@ impl Database for PgDatabase {
         .fetch_all(&self.pool)
         .await
     }
+
+    async fn fetch_processes(&self, machine_id: i64) -> Result<Vec<Process>, Error> {
+        sqlx::query_as::<_, Process>(
+            "
+        SELECT name, pids
+            FROM processes
+            WHERE machine_id = $1
+            ",
+        )
+        .bind(machine_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn fetch_cgroups(&self, machine_id: i64) -> Result<Vec<Cgroup>, Error> {
+        sqlx::query_as::<_, Cgroup>(
+            "
+        SELECT path, cpu_usage_usec, memory_current, memory_max,
+               io_read_bytes, io_write_bytes
+            FROM cgroup_statistics
+            WHERE machine_id = $1
+            ",
+        )
+        .bind(machine_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn fetch_probe_results(&self, machine_id: i64) -> Result<Vec<ProbeResult>, Error> {
+        sqlx::query_as::<_, ProbeResult>(
+            "
+        SELECT name, target, success, latency_ms, status_code, error
+            FROM (
+                SELECT DISTINCT ON (name) *
+                    FROM probe_results
+                    WHERE machine_id = $1
+                    ORDER BY name, created_at DESC
+            ) AS latest
+            WHERE NOT removed
+            ",
+        )
+        .bind(machine_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn fetch_machine(&self, machine_id: i64) -> Result<Option<Machine>, Error> {
+        let row = sqlx::query(
+            "
+        SELECT id, host(ip) AS ip, hostname,
+               EXTRACT(EPOCH FROM boot_time)::BIGINT AS boot_time, token_hash,
+               report_interval
+            FROM machines
+            WHERE id = $1
+            ",
+        )
+        .bind(machine_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(Machine {
+                id: row.try_get("id")?,
+                ip: row.try_get("ip")?,
+                hostname: row.try_get("hostname")?,
+                boot_time: row.try_get("boot_time")?,
+                token_hash: row.try_get("token_hash")?,
+                report_interval: row.try_get("report_interval")?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_machine(&self, machine: &Machine) -> Result<bool, Error> {
+        let result = sqlx::query(
+            "
+        INSERT INTO machines (id, ip, hostname, boot_time, token_hash, report_interval)
+            VALUES ($1, $2::INET, $3, to_timestamp($4), $5, $6)
+            ON CONFLICT (id) DO NOTHING
+            ",
+        )
+        .bind(machine.id)
+        .bind(&machine.ip)
+        .bind(&machine.hostname)
+        .bind(machine.boot_time)
+        .bind(&machine.token_hash)
+        .bind(machine.report_interval)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn update_machine(&self, machine: &Machine, registered: &Machine) -> Result<bool, Error> {
+        // Only if nobody else registered the machine in the meantime
+        let result = sqlx::query(
+            "
+        UPDATE machines SET
+                ip = $2::INET,
+                hostname = $3,
+                boot_time = to_timestamp($4),
+                report_interval = $6
+            WHERE id = $1
+                AND token_hash = $5
+                AND host(ip) IS NOT DISTINCT FROM $7
+                AND hostname = $8
+                AND EXTRACT(EPOCH FROM boot_time)::BIGINT = $9
+            ",
+        )
+        .bind(machine.id)
+        .bind(&machine.ip)
+        .bind(&machine.hostname)
+        .bind(machine.boot_time)
+        .bind(&machine.token_hash)
+        .bind(machine.report_interval)
+        .bind(&registered.ip)
+        .bind(&registered.hostname)
+        .bind(registered.boot_time)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_machines(&self, token_hash: &str) -> Result<Vec<MachineInfo>, Error> {
+        let rows = sqlx::query(
+            "
+        SELECT id, COALESCE(host(ip), '') AS ip, hostname,
+               EXTRACT(EPOCH FROM last_seen)::BIGINT AS last_seen, status
+            FROM machines
+            WHERE token_hash = $1
+            ORDER BY id
+            ",
+        )
+        .bind(token_hash)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let last_seen: Option<i64> = row.try_get("last_seen")?;
+                let status: String = row.try_get("status")?;
+                Ok(MachineInfo {
+                    machine_id: row.try_get("id")?,
+                    hostname: row.try_get("hostname")?,
+                    ip: row.try_get("ip")?,
+                    last_seen: last_seen
+                        .map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+                    status: machine_status_from_str(&status).into(),
+                })
+            })
+            .collect()
+    }
+
+    async fn mark_machines_seen(
+        &self,
+        machine_ids: &[i64],
+    ) -> Result<Vec<(i64, MachineStatus)>, Error> {
+        // The subquery still sees the rows as they were before
+        let rows = sqlx::query(
+            "
+        UPDATE machines SET last_seen = NOW(), status = 'online'
+            FROM (SELECT id, status FROM machines WHERE id = ANY($1)) AS previous
+            WHERE machines.id = previous.id
+            RETURNING machines.id, previous.status AS previous_status
+            ",
+        )
+        .bind(machine_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .filter_map(|row| {
+                let previous_status: String = match row.try_get("previous_status") {
+                    Ok(previous_status) => previous_status,
+                    Err(e) => return Some(Err(e)),
+                };
+                match machine_status_from_str(&previous_status) {
+                    MachineStatus::Online => None,
+                    previous_status => Some(row.try_get("id").map(|id| (id, previous_status))),
+                }
+            })
+            .collect()
+    }
+
+    async fn sweep_machine_status(
+        &self,
+        stale_after: f64,
+        offline_after: f64,
+    ) -> Result<Vec<SilentMachine>, Error> {
+        let rows = sqlx::query(
+            "
+        WITH silent AS (
+            SELECT id,
+                   EXTRACT(EPOCH FROM NOW() - last_seen)::DOUBLE PRECISION AS silent_secs
+                FROM machines
+                WHERE last_seen IS NOT NULL
+        ), current AS (
+            SELECT machines.id, silent.silent_secs, machines.status AS previous_status,
+                   CASE
+                       WHEN silent_secs > report_interval * $2 THEN 'offline'
+                       WHEN silent_secs > report_interval * $1 THEN 'stale'
+                       ELSE 'online'
+                   END AS status
+                FROM machines JOIN silent ON machines.id = silent.id
+        )
+        UPDATE machines SET status = current.status
+            FROM current
+            WHERE machines.id = current.id
+                AND machines.status <> current.status
+                AND current.status <> 'online'
+            RETURNING machines.id, current.previous_status, machines.status,
+                      machines.report_interval, current.silent_secs
+            ",
+        )
+        .bind(stale_after)
+        .bind(offline_after)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let previous_status: String = row.try_get("previous_status")?;
+                let status: String = row.try_get("status")?;
+                Ok(SilentMachine {
+                    id: row.try_get("id")?,
+                    previous_status: machine_status_from_str(&previous_status),
+                    status: machine_status_from_str(&status),
+                    silent_secs: row.try_get("silent_secs")?,
+                    report_interval: row.try_get("report_interval")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn fetch_open_alerts(&self) -> Result<Vec<Alert>, Error> {
+        let rows = sqlx::query(
+            "
+        SELECT rule, machine_id, series, value, threshold
+            FROM alerts
+            WHERE resolved_at IS NULL
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Alert {
+                    rule: row.try_get("rule")?,
+                    machine_id: row.try_get("machine_id")?,
+                    series: row.try_get("series")?,
+                    value: row.try_get("value")?,
+                    threshold: row.try_get("threshold")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn open_alert(&self, alert: &Alert) -> Result<(), Error> {
+        // The conflict means the alert is already open
+        sqlx::query(
+            "
+        INSERT INTO alerts (rule, machine_id, series, value, threshold)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (rule, machine_id, series) WHERE resolved_at IS NULL
+            DO NOTHING
+            ",
+        )
+        .bind(&alert.rule)
+        .bind(alert.machine_id)
+        .bind(&alert.series)
+        .bind(alert.value)
+        .bind(alert.threshold)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn resolve_alert(&self, alert: &Alert) -> Result<(), Error> {
+        sqlx::query(
+            "
+        UPDATE alerts SET resolved_at = NOW()
+            WHERE rule = $1 AND machine_id = $2 AND series = $3
+                AND resolved_at IS NULL
+            ",
+        )
+        .bind(&alert.rule)
+        .bind(alert.machine_id)
+        .bind(&alert.series)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn save_dead_letter(
+        &self,
+        url: &str,
+        payload: &str,
+        error: &str,
+        attempts: i32,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "
+        INSERT INTO webhook_dead_letters (url, payload, error, attempts)
+            VALUES ($1, $2::JSONB, $3, $4)
+            ",
+        )
+        .bind(url)
+        .bind(payload)
+        .bind(error)
+        .bind(attempts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn roll_up_statistics(&self) -> Result<(), Error> {
+        for query in ROLLUP_QUERIES {
+            sqlx::query(query).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_expired_statistics(&self, retention: &RetentionPolicy) -> Result<u64, Error> {
+        let hypertables = self.fetch_hypertables().await?;
+        let mut n_deleted = 0;
+        for (table, column, keep) in retention.expirations() {
+            // Dropping whole chunks is much cheaper than deleting rows
+            // and works on compressed chunks
+            if hypertables.contains(table) {
+                let n_chunks: i64 = sqlx::query(
+                    "
+        SELECT COUNT(*) AS n_chunks
+            FROM drop_chunks($1::REGCLASS, older_than => NOW() - make_interval(secs => $2))
+                    ",
+                )
+                .bind(table)
+                .bind(keep.as_secs() as f64)
+                .fetch_one(&self.pool)
+                .await?
+                .try_get("n_chunks")?;
+                n_deleted += n_chunks as u64;
+                continue;
+            }
+
+            let result = sqlx::query(&format!(
+                "DELETE FROM {table} WHERE {column} < NOW() - make_interval(secs => $1)"
+            ))
+            .bind(keep.as_secs() as f64)
+            .execute(&self.pool)
+            .await?;
+            n_deleted += result.rows_affected();
+        }
+        Ok(n_deleted)
+    }
+
+    async fn fetch_cpu_statistics(
+        &self,
+        machine_id: i64,
+        from: i64,
+        to: i64,
+        resolution: Resolution,
+    ) -> Result<Vec<CpuSample>, Error> {
+        let query = match resolution {
+            Resolution::Raw => "
+        SELECT EXTRACT(EPOCH FROM created_at)::BIGINT AS time,
+               usage AS usage_avg, usage AS usage_max,
+               temperature AS temperature_avg, temperature AS temperature_max
+            FROM cpu_statistics
+            WHERE machine_id = $1
+                AND created_at BETWEEN to_timestamp($2) AND to_timestamp($3)
+            ORDER BY created_at
+            "
+            .to_string(),
+            _ => format!(
+                "
+        SELECT EXTRACT(EPOCH FROM bucket)::BIGINT AS time,
+               usage_avg, usage_max, temperature_avg, temperature_max
+            FROM cpu_statistics{}
+            WHERE machine_id = $1
+                AND bucket BETWEEN to_timestamp($2) AND to_timestamp($3)
+            ORDER BY bucket
+            ",
+                resolution.table_suffix()
+            ),
+        };
+
+        let rows = sqlx::query(&query)
+            .bind(machine_id)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let usage: Option<f64> = row.try_get("usage_avg")?;
+                let usage_max: Option<f64> = row.try_get("usage_max")?;
+                let temp: Option<f64> = row.try_get("temperature_avg")?;
+                let temp_max: Option<f64> = row.try_get("temperature_max")?;
+                Ok(CpuSample {
+                    time: Some(prost_types::Timestamp {
+                        seconds: row.try_get("time")?,
+                        nanos: 0,
+                    }),
+                    usage: usage.unwrap_or_default() as f32,
+                    usage_max: usage_max.unwrap_or_default() as f32,
+                    temp: temp.unwrap_or_default() as f32,
+                    temp_max: temp_max.unwrap_or_default() as f32,
+                })
+            })
+            .collect()
+    }
+
+    async fn fetch_memory_statistics(
+        &self,
+        machine_id: i64,
+        from: i64,
+        to: i64,
+        resolution: Resolution,
+    ) -> Result<Vec<MemorySample>, Error> {
+        let query = match resolution {
+            Resolution::Raw => "
+        SELECT EXTRACT(EPOCH FROM created_at)::BIGINT AS time,
+               total, free AS free_avg, free AS free_min
+            FROM memory_statistics
+            WHERE machine_id = $1
+                AND created_at BETWEEN to_timestamp($2) AND to_timestamp($3)
+            ORDER BY created_at
+            "
+            .to_string(),
+            _ => format!(
+                "
+        SELECT EXTRACT(EPOCH FROM bucket)::BIGINT AS time, total, free_avg, free_min
+            FROM memory_statistics{}
+            WHERE machine_id = $1
+                AND bucket BETWEEN to_timestamp($2) AND to_timestamp($3)
+            ORDER BY bucket
+            ",
+                resolution.table_suffix()
+            ),
+        };
+
+        let rows = sqlx::query(&query)
+            .bind(machine_id)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(MemorySample {
+                    time: Some(prost_types::Timestamp {
+                        seconds: row.try_get("time")?,
+                        nanos: 0,
+                    }),
+                    total: row.try_get("total")?,
+                    free: row.try_get("free_avg")?,
+                    free_min: row.try_get("free_min")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn fetch_recent_statistics(
+        &self,
+        since: i64,
+    ) -> Result<Vec<(i64, ChangeEventBatch)>, Error> {
+        let cpu_rows = sqlx::query(
+            "
+        SELECT machine_id, EXTRACT(EPOCH FROM created_at)::BIGINT AS time, usage, temperature
+            FROM cpu_statistics
+            WHERE created_at >= to_timestamp($1)
+            ",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        let memory_rows = sqlx::query(
+            "
+        SELECT machine_id, EXTRACT(EPOCH FROM created_at)::BIGINT AS time, total, free
+            FROM memory_statistics
+            WHERE created_at >= to_timestamp($1)
+            ",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let batch = |machine_id, event| ChangeEventBatch {
+            events: vec![ChangeEvent {
+                event_type: EventType::Update.into(),
+                event: Some(event),
+            }],
+            machine_id,
+            sequence: 0,
+        };
+        let mut statistics = vec![];
+        for row in cpu_rows {
+            let usage: Option<f64> = row.try_get("usage")?;
+            let temp: Option<f64> = row.try_get("temperature")?;
+            let event = Event::Cpu(CpuChangeEvent {
+                usage: usage.unwrap_or_default() as f32,
+                temp: temp.unwrap_or_default() as f32,
+            });
+            statistics.push((
+                row.try_get("time")?,
+                batch(row.try_get("machine_id")?, event),
+            ));
+        }
+        for row in memory_rows {
+            let event = Event::Memory(MemoryChangeEvent {
+                total: row.try_get("total")?,
+                free: row.try_get("free")?,
+            });
+            statistics.push((
+                row.try_get("time")?,
+                batch(row.try_get("machine_id")?, event),
+            ));
+        }
+        statistics.sort_by_key(|(time, _)| *time);
+
+        Ok(statistics)
+    }
+
+    async fn check_health(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(hostname: &str, ip: Option<&str>, boot_time: i64, token_hash: &str) -> Machine {
+        Machine {
+            id: 1,
+            ip: ip.map(str::to_string),
+            hostname: hostname.to_string(),
+            boot_time,
+            token_hash: token_hash.to_string(),
+            report_interval: 5,
+        }
+    }
+
+    #[test]
+    fn other_token_is_other_host() {
+        let registered = machine("web", Some("10.0.0.1"), 1000, "a");
+        assert!(!registered.is_same_host(&machine("web", Some("10.0.0.1"), 1000, "b")));
+        assert!(!registered.is_same_host(&machine("web", Some("10.0.0.1"), 5000, "b")));
+    }
+
+    #[test]
+    fn reboot_keeps_name_or_address() {
+        let registered = machine("web", Some("10.0.0.1"), 1000, "a");
+        assert!(registered.is_same_host(&machine("web", Some("10.0.0.2"), 5000, "a")));
+        assert!(registered.is_same_host(&machine("db", Some("10.0.0.1"), 5000, "a")));
+        assert!(!registered.is_same_host(&machine("db", Some("10.0.0.2"), 5000, "a")));
+        // an unknown address is no evidence
+        let registered = machine("web", None, 1000, "a");
+        assert!(!registered.is_same_host(&machine("db", None, 5000, "a")));
+    }
+
+    #[test]
+    fn rename_without_reboot() {
+        let registered = machine("web", Some("10.0.0.1"), 1000, "a");
+        assert!(registered.is_same_host(&machine("db", Some("10.0.0.2"), 1000, "a")));
+    }
+
+    #[test]
+    fn boot_time_jitters() {
+        let registered = machine("web", Some("10.0.0.1"), 1000, "a");
+        let tolerance = BOOT_TIME_TOLERANCE;
+        assert!(registered.is_same_host(&machine("db", None, 1000 - tolerance, "a")));
+        assert!(registered.is_same_host(&machine("db", None, 1000 + tolerance, "a")));
+        // booted back in time
+        assert!(!registered.is_same_host(&machine(
+            "web",
+            Some("10.0.0.1"),
+            1000 - tolerance - 1,
+            "a"
+        )));
+        // rebooted, both name and address changed
+        assert!(!registered.is_same_host(&machine("db", None, 1000 + tolerance + 1, "a")));
+    }
 }
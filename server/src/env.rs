@@ -8,3 +8,10 @@ pub fn get_db_username() -> String {
 pub fn get_db_password() -> String {
     tc_core::get_env_var_or_panic(ENV_DB_PASSWORD)
 }
+
+const ENV_WEBHOOK_SECRET: &str = "TEACUP_WEBHOOK_SECRET";
+
+/// Secret to sign webhook payloads with, they stay unsigned without
+pub fn get_webhook_secret() -> Option<String> {
+    std::env::var(ENV_WEBHOOK_SECRET).ok()
+}
@@ -0,0 +1,64 @@
+use super::MetricService;
+use protocol::event_service_server::EventServiceServer;
+use std::time::Duration;
+use tc_core::wait_for_shutdown;
+use tokio::sync::watch;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, warn};
+
+/// Periodically reports the service as NOT_SERVING to health checks
+/// while the database can't be reached. A check taking longer than
+/// the interval counts as failed. Once shutting down, the service
+/// stays NOT_SERVING so that load balancers move on.
+pub async fn report_health(
+    mut health_reporter: HealthReporter,
+    service: MetricService,
+    check_interval: Duration,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(check_interval);
+    let mut was_healthy = None;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = wait_for_shutdown(&mut shutdown_rx) => {
+                info!("Reporting NOT_SERVING while shutting down");
+                set_status(&mut health_reporter, ServingStatus::NotServing).await;
+                return;
+            }
+        }
+
+        let is_healthy = match tokio::time::timeout(check_interval, service.is_healthy()).await {
+            Ok(is_healthy) => is_healthy,
+            Err(_) => {
+                warn!(timeout = ?check_interval, "Database health check timed out");
+                false
+            }
+        };
+        if was_healthy == Some(is_healthy) {
+            continue;
+        }
+        was_healthy = Some(is_healthy);
+
+        let status = if is_healthy {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        info!(?status, "Health status changed");
+        set_status(&mut health_reporter, status).await;
+    }
+}
+
+async fn set_status(health_reporter: &mut HealthReporter, status: ServingStatus) {
+    // "" is the overall health of the server
+    health_reporter.set_service_status("", status).await;
+    health_reporter
+        .set_service_status(
+            <EventServiceServer<MetricService> as tonic::transport::NamedService>::NAME,
+            status,
+        )
+        .await;
+}
@@ -0,0 +1,468 @@
+extern crate protocol as proto;
+
+use super::database::Database;
+use super::machine_status::MachineStatusTracker;
+use super::metrics::Metrics;
+use proto::ChangeEventBatch;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tc_core::wait_for_shutdown;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+/// Batches a writer takes from the queue for a single database write
+const MAX_COALESCED_BATCHES: usize = 256;
+/// What we ask clients to wait if the queue is full
+pub const QUEUE_FULL_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Sequence numbers of written batches remembered per machine
+const REMEMBERED_SEQUENCES: usize = 1024;
+
+// Sequence numbers of recently written batches per machine
+type PersistedSequences = Arc<std::sync::Mutex<HashMap<i64, BTreeSet<i64>>>>;
+
+struct QueuedBatch {
+    batch: ChangeEventBatch,
+    // tells the sender once the batch is in the database, dropped if
+    // writing failed
+    persisted: oneshot::Sender<()>,
+}
+
+/// Size of the queue and number of writers draining it
+#[derive(Debug, Clone, Copy)]
+pub struct IngestionConfig {
+    pub queue_size: usize,
+    pub n_writers: usize,
+}
+
+pub enum EnqueueError {
+    Full,
+    Closed,
+}
+
+/// Decouples requests from database writes. Writers drain the queue
+/// and write the batches of many machines at once, each sender hears
+/// back once its batch is stored.
+///
+/// The queue is split into one shard per writer by machine id, so that
+/// the batches of a machine are written in order.
+#[derive(Clone, Debug)]
+pub struct IngestionQueue {
+    shards: Arc<[mpsc::Sender<QueuedBatch>]>,
+    metrics: Arc<Metrics>,
+    // clones keep the sender alive, thus closing is explicit
+    close_tx: Arc<watch::Sender<bool>>,
+    writers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    persisted_sequences: PersistedSequences,
+}
+
+impl std::fmt::Debug for QueuedBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueuedBatch")
+            .field("machine_id", &self.batch.machine_id)
+            .finish()
+    }
+}
+
+impl IngestionQueue {
+    pub fn new(
+        db: Arc<dyn Database>,
+        machine_status: MachineStatusTracker,
+        metrics: Arc<Metrics>,
+        config: IngestionConfig,
+    ) -> Self {
+        let n_writers = config.n_writers.max(1);
+        let shard_size = config.queue_size.div_ceil(n_writers).max(1);
+        metrics
+            .ingestion_queue_capacity
+            .store((shard_size * n_writers) as i64, Ordering::Relaxed);
+
+        let (close_tx, close_rx) = watch::channel(false);
+        let persisted_sequences = PersistedSequences::default();
+        let mut shards = vec![];
+        let mut writers = vec![];
+        for _ in 0..n_writers {
+            let (tx, rx) = mpsc::channel(shard_size);
+            shards.push(tx);
+            writers.push(tokio::task::spawn(write_batches(
+                rx,
+                close_rx.clone(),
+                db.clone(),
+                machine_status.clone(),
+                metrics.clone(),
+                persisted_sequences.clone(),
+            )));
+        }
+
+        IngestionQueue {
+            shards: shards.into(),
+            metrics,
+            close_tx: Arc::new(close_tx),
+            writers: Arc::new(Mutex::new(writers)),
+            persisted_sequences,
+        }
+    }
+
+    /// Whether a numbered batch of the machine was written already,
+    /// e.g. as the client sent it again after missing the ack. Only
+    /// the latest sequence numbers of each machine are remembered, and
+    /// only until the server restarts.
+    pub fn is_persisted(&self, machine_id: i64, sequence: i64) -> bool {
+        sequence != 0
+            && self
+                .persisted_sequences
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .get(&machine_id)
+                .is_some_and(|sequences| sequences.contains(&sequence))
+    }
+
+    /// Refuses further batches and waits until the writers stored the
+    /// queued ones
+    pub async fn close(&self) {
+        let _ = self.close_tx.send(true);
+        for writer in self.writers.lock().await.drain(..) {
+            if let Err(e) = writer.await {
+                error!(error = %e, "Ingestion writer panicked");
+            }
+        }
+    }
+
+    /// Queues the batch, the receiver resolves once it is written and
+    /// fails if it couldn't be
+    pub fn enqueue_tracked(
+        &self,
+        batch: ChangeEventBatch,
+    ) -> Result<oneshot::Receiver<()>, EnqueueError> {
+        let (persisted_tx, persisted_rx) = oneshot::channel();
+        self.push(QueuedBatch {
+            batch,
+            persisted: persisted_tx,
+        })?;
+        Ok(persisted_rx)
+    }
+
+    fn push(&self, queued_batch: QueuedBatch) -> Result<(), EnqueueError> {
+        let shard = queued_batch
+            .batch
+            .machine_id
+            .rem_euclid(self.shards.len() as i64) as usize;
+        match self.shards[shard].try_send(queued_batch) {
+            Ok(_) => {
+                self.metrics
+                    .ingestion_queue_depth
+                    .fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.metrics
+                    .rejected_batches
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(EnqueueError::Full)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(EnqueueError::Closed),
+        }
+    }
+}
+
+async fn write_batches(
+    mut rx: mpsc::Receiver<QueuedBatch>,
+    mut close_rx: watch::Receiver<bool>,
+    db: Arc<dyn Database>,
+    machine_status: MachineStatusTracker,
+    metrics: Arc<Metrics>,
+    persisted_sequences: PersistedSequences,
+) {
+    loop {
+        // Take whatever piled up during the last write
+        let queued_batches = {
+            let first = tokio::select! {
+                queued_batch = rx.recv() => queued_batch,
+                _ = wait_for_shutdown(&mut close_rx) => {
+                    // Still write what is queued already
+                    rx.close();
+                    rx.recv().await
+                }
+            };
+            let first = match first {
+                Some(queued_batch) => queued_batch,
+                None => return,
+            };
+            let mut queued_batches = vec![first];
+            while queued_batches.len() < MAX_COALESCED_BATCHES {
+                match rx.try_recv() {
+                    Ok(queued_batch) => queued_batches.push(queued_batch),
+                    Err(_) => break,
+                }
+            }
+            queued_batches
+        };
+        let n_batches = queued_batches.len();
+        metrics
+            .ingestion_queue_depth
+            .fetch_sub(n_batches as i64, Ordering::Relaxed);
+
+        let (batches, persisted): (Vec<_>, Vec<_>) = queued_batches
+            .into_iter()
+            .map(|queued_batch| (queued_batch.batch, queued_batch.persisted))
+            .unzip();
+        debug!(n_batches, "Writing batches");
+        let written = match db.process_events(&batches).await {
+            Ok(_) => vec![true; n_batches],
+            // A single bad batch shouldn't fail the others, thus each
+            // gets its own transaction
+            Err(e) if n_batches > 1 => {
+                warn!(error = %e, n_batches, "Failed to write batches, retrying one by one");
+                let mut written = vec![];
+                for batch in &batches {
+                    let result = db.process_events(std::slice::from_ref(batch)).await;
+                    if let Err(e) = &result {
+                        error!(error = %e, machine_id = batch.machine_id, "Failed to write batch");
+                    }
+                    written.push(result.is_ok());
+                }
+                written
+            }
+            Err(e) => {
+                error!(error = %e, machine_id = batches[0].machine_id, "Failed to write batch");
+                vec![false]
+            }
+        };
+        // Machines whose data got lost don't count as online
+        let machine_ids: Vec<i64> = batches
+            .iter()
+            .zip(&written)
+            .filter(|(_, written)| **written)
+            .map(|(batch, _)| batch.machine_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if !machine_ids.is_empty() {
+            machine_status.mark_seen(&machine_ids).await;
+        }
+        metrics.ingestion_writes.fetch_add(1, Ordering::Relaxed);
+
+        remember_sequences(&persisted_sequences, &batches, &written);
+        for (persisted, written) in persisted.into_iter().zip(written) {
+            // Dropping `persisted` tells the sender
+            if !written {
+                metrics.failed_batches.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            metrics.ingested_batches.fetch_add(1, Ordering::Relaxed);
+            // the sender may have given up waiting
+            let _ = persisted.send(());
+        }
+    }
+}
+
+fn remember_sequences(
+    persisted_sequences: &PersistedSequences,
+    batches: &[ChangeEventBatch],
+    written: &[bool],
+) {
+    let mut persisted_sequences = persisted_sequences
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (batch, _) in batches
+        .iter()
+        .zip(written)
+        .filter(|(batch, written)| **written && batch.sequence != 0)
+    {
+        let sequences = persisted_sequences.entry(batch.machine_id).or_default();
+        sequences.insert(batch.sequence);
+        if sequences.len() > REMEMBERED_SEQUENCES {
+            sequences.pop_first();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::notifier::Notifier;
+    use super::super::test_database::TestDatabase;
+    use super::*;
+
+    fn queue(db: &Arc<TestDatabase>, config: IngestionConfig) -> (IngestionQueue, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let machine_status =
+            MachineStatusTracker::new(db.clone(), Notifier::new(vec![], None, db.clone()));
+        let queue = IngestionQueue::new(db.clone(), machine_status, metrics.clone(), config);
+        (queue, metrics)
+    }
+
+    fn single_writer(queue_size: usize) -> IngestionConfig {
+        IngestionConfig {
+            queue_size,
+            n_writers: 1,
+        }
+    }
+
+    fn batch(machine_id: i64, sequence: i64) -> ChangeEventBatch {
+        ChangeEventBatch {
+            machine_id,
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    // Writers only run once the test awaits, thus everything enqueued
+    // before is there for their first write
+    fn enqueue(queue: &IngestionQueue, batches: &[(i64, i64)]) -> Vec<oneshot::Receiver<()>> {
+        batches
+            .iter()
+            .map(|&(machine_id, sequence)| {
+                queue
+                    .enqueue_tracked(batch(machine_id, sequence))
+                    .unwrap_or_else(|_| panic!("Failed to enqueue batch"))
+            })
+            .collect()
+    }
+
+    async fn outcomes(persisted: Vec<oneshot::Receiver<()>>) -> Vec<bool> {
+        let mut outcomes = vec![];
+        for persisted in persisted {
+            outcomes.push(persisted.await.is_ok());
+        }
+        outcomes
+    }
+
+    #[tokio::test]
+    async fn coalesces_queued_batches() {
+        let db = Arc::new(TestDatabase::default());
+        let (queue, metrics) = queue(&db, single_writer(16));
+
+        let persisted = enqueue(&queue, &[(1, 0), (2, 0), (1, 0)]);
+        assert_eq!(metrics.ingestion_queue_depth.load(Ordering::Relaxed), 3);
+        queue.close().await;
+
+        assert_eq!(outcomes(persisted).await, vec![true; 3]);
+        assert_eq!(*db.writes.lock().unwrap(), vec![vec![1, 2, 1]]);
+        assert_eq!(*db.seen_machines.lock().unwrap(), vec![vec![1, 2]]);
+        assert_eq!(metrics.ingestion_queue_depth.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.ingestion_writes.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.ingested_batches.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn shards_by_machine() {
+        let db = Arc::new(TestDatabase::default());
+        let config = IngestionConfig {
+            queue_size: 16,
+            n_writers: 2,
+        };
+        let (queue, _) = queue(&db, config);
+
+        enqueue(&queue, &[(1, 1), (2, 1), (-1, 1), (1, 2), (4, 1), (1, 3)]);
+        queue.close().await;
+
+        let writes = db.writes.lock().unwrap();
+        assert_eq!(writes.len(), 2);
+        for write in writes.iter() {
+            let shard = write[0].rem_euclid(2);
+            assert!(write
+                .iter()
+                .all(|machine_id| machine_id.rem_euclid(2) == shard));
+        }
+        // the batches of a machine keep their order
+        let sequences: Vec<i64> = db
+            .written_batches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|batch| batch.machine_id == 1)
+            .map(|batch| batch.sequence)
+            .collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn retries_one_by_one_after_failed_write() {
+        let db = Arc::new(TestDatabase::default());
+        db.failing_machines.lock().unwrap().insert(2);
+        let (queue, metrics) = queue(&db, single_writer(16));
+
+        let persisted = enqueue(&queue, &[(1, 1), (2, 1), (3, 1)]);
+        queue.close().await;
+
+        assert_eq!(outcomes(persisted).await, vec![true, false, true]);
+        assert_eq!(
+            *db.writes.lock().unwrap(),
+            vec![vec![1, 2, 3], vec![1], vec![2], vec![3]]
+        );
+        // machines whose data got lost aren't seen
+        assert_eq!(*db.seen_machines.lock().unwrap(), vec![vec![1, 3]]);
+        assert_eq!(metrics.ingested_batches.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.failed_batches.load(Ordering::Relaxed), 1);
+        assert!(queue.is_persisted(1, 1));
+        assert!(!queue.is_persisted(2, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_single_batch_is_not_retried() {
+        let db = Arc::new(TestDatabase::default());
+        db.failing_machines.lock().unwrap().insert(2);
+        let (queue, metrics) = queue(&db, single_writer(16));
+
+        let persisted = enqueue(&queue, &[(2, 1)]);
+        queue.close().await;
+
+        assert_eq!(outcomes(persisted).await, vec![false]);
+        assert_eq!(*db.writes.lock().unwrap(), vec![vec![2]]);
+        assert!(db.seen_machines.lock().unwrap().is_empty());
+        assert_eq!(metrics.failed_batches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn remembers_latest_sequences() {
+        let db = Arc::new(TestDatabase::default());
+        let n_batches = REMEMBERED_SEQUENCES as i64 + 1;
+        let (queue, _) = queue(&db, single_writer(n_batches as usize + 1));
+
+        let mut batches: Vec<(i64, i64)> = (1..=n_batches).map(|sequence| (1, sequence)).collect();
+        batches.push((2, 0));
+        enqueue(&queue, &batches);
+        queue.close().await;
+
+        // the oldest one is forgotten
+        assert!(!queue.is_persisted(1, 1));
+        assert!(queue.is_persisted(1, 2));
+        assert!(queue.is_persisted(1, n_batches));
+        assert!(!queue.is_persisted(1, n_batches + 1));
+        // unnumbered batches are never skipped
+        assert!(!queue.is_persisted(2, 0));
+        assert!(!queue.is_persisted(3, 2));
+    }
+
+    #[tokio::test]
+    async fn rejects_batches_if_full() {
+        let db = Arc::new(TestDatabase::default());
+        let (queue, metrics) = queue(&db, single_writer(1));
+
+        enqueue(&queue, &[(1, 1)]);
+        assert!(matches!(
+            queue.enqueue_tracked(batch(1, 2)),
+            Err(EnqueueError::Full)
+        ));
+        assert_eq!(metrics.rejected_batches.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.ingestion_queue_capacity.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn close_drains_the_queue() {
+        let db = Arc::new(TestDatabase::default());
+        let (queue, _) = queue(&db, single_writer(16));
+
+        let persisted = enqueue(&queue, &[(1, 1), (2, 1)]);
+        queue.close().await;
+
+        assert_eq!(outcomes(persisted).await, vec![true, true]);
+        assert_eq!(db.written_batches.lock().unwrap().len(), 2);
+        assert!(matches!(
+            queue.enqueue_tracked(batch(1, 2)),
+            Err(EnqueueError::Closed)
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+extern crate protocol as proto;
+
+use super::database::{Alert, Database, SilentMachine};
+use super::notifier::{Notification, Notifier};
+use proto::MachineStatus;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Names of the alerts about machines which stopped reporting
+const STALE_RULE: &str = "machine_stale";
+const OFFLINE_RULE: &str = "machine_offline";
+
+/// Tracks when machines reported last and alerts about those which
+/// went quiet.
+#[derive(Debug, Clone)]
+pub struct MachineStatusTracker {
+    db: Arc<dyn Database>,
+    notifier: Notifier,
+}
+
+impl MachineStatusTracker {
+    pub fn new(db: Arc<dyn Database>, notifier: Notifier) -> Self {
+        MachineStatusTracker { db, notifier }
+    }
+
+    /// Called whenever machines report, resolves their alerts if they
+    /// were quiet before
+    pub async fn mark_seen(&self, machine_ids: &[i64]) {
+        let quiet_machines = match self.db.mark_machines_seen(machine_ids).await {
+            Ok(quiet_machines) => quiet_machines,
+            Err(e) => {
+                error!(error = %e, "Failed to save when machines were last seen");
+                return;
+            }
+        };
+
+        for (machine_id, previous_status) in quiet_machines {
+            info!(machine_id, "Machine is reporting again");
+            self.resolve(machine_id, STALE_RULE).await;
+            if previous_status == MachineStatus::Offline {
+                self.resolve(machine_id, OFFLINE_RULE).await;
+            }
+        }
+    }
+
+    /// Marks machines stale after missing `stale_after` times their
+    /// report interval and offline after `offline_after` times
+    pub async fn sweep(&self, stale_after: f64, offline_after: f64) {
+        let machines = match self
+            .db
+            .sweep_machine_status(stale_after, offline_after)
+            .await
+        {
+            Ok(machines) => machines,
+            Err(e) => {
+                error!(error = %e, "Failed to update the status of machines");
+                return;
+            }
+        };
+
+        for machine in machines {
+            warn!(
+                machine_id = machine.id,
+                status = ?machine.status,
+                silent_secs = machine.silent_secs,
+                "Machine stopped reporting"
+            );
+            // A machine may skip being stale if we didn't sweep in time
+            if machine.previous_status == MachineStatus::Online {
+                self.open(&machine, STALE_RULE, stale_after).await;
+            }
+            if machine.status == MachineStatus::Offline {
+                self.open(&machine, OFFLINE_RULE, offline_after).await;
+            }
+        }
+    }
+
+    async fn open(&self, machine: &SilentMachine, rule: &str, multiple: f64) {
+        let alert = Alert {
+            rule: rule.to_string(),
+            machine_id: machine.id,
+            series: String::new(),
+            value: machine.silent_secs,
+            threshold: f64::from(machine.report_interval) * multiple,
+        };
+        if let Err(e) = self.db.open_alert(&alert).await {
+            error!(error = %e, "Failed to save alert to database");
+        }
+        self.notifier.notify(Notification::alert_fired(&alert));
+    }
+
+    async fn resolve(&self, machine_id: i64, rule: &str) {
+        let alert = Alert {
+            rule: rule.to_string(),
+            machine_id,
+            series: String::new(),
+            value: 0.,
+            threshold: 0.,
+        };
+        if let Err(e) = self.db.resolve_alert(&alert).await {
+            error!(error = %e, "Failed to resolve alert in database");
+        }
+        self.notifier.notify(Notification::alert_resolved(&alert));
+    }
+}
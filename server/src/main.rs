@@ -2,33 +2,276 @@ mod env;
 mod metric_service;
 
 use clap::Parser;
-use env::{get_db_password, get_db_username};
-use metric_service::MetricService;
+use env::{get_db_password, get_db_username, get_webhook_secret};
+use metric_service::{
+    load_alert_rules, load_tls_config, report_health, serve_metrics, IngestionConfig,
+    MachineIdConflict, MetricService, Metrics, RateLimiter, RetentionPolicy,
+};
 use protocol::event_service_server::EventServiceServer;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tc_core::{init_logging, spawn_shutdown_listener, wait_for_shutdown};
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 struct ServerCli {
     #[clap(short = 'p', long, value_parser, default_value_t = 50055)]
     port: u16,
+    /// Server certificate (PEM) which enables TLS
+    #[clap(long, value_parser, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of the server certificate
+    #[clap(long, value_parser, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// CA certificate (PEM) which client certificates must be signed
+    /// with. Enables mutual TLS.
+    #[clap(long, value_parser, requires = "tls-cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// What to do if a second host claims an existing machine id, e.g.
+    /// one with another auth token.
+    /// Reassigning doesn't work with client certificates, as they are
+    /// bound to the machine id the host had before.
+    #[clap(long, value_enum, default_value_t = MachineIdConflict::Reject)]
+    machine_id_conflict: MachineIdConflict,
+    /// Requests per second allowed on average per machine, 0 disables
+    /// rate limiting
+    #[clap(long, value_parser, default_value_t = 1.)]
+    rate_limit: f64,
+    /// Requests allowed in a burst per machine
+    #[clap(long, value_parser, default_value_t = 10)]
+    rate_limit_burst: u32,
+    /// Requests per second allowed on average per auth token, which
+    /// all machines using the token share. 0 disables the limit.
+    #[clap(long, value_parser, default_value_t = 0.)]
+    token_rate_limit: f64,
+    /// Requests allowed in a burst per auth token
+    #[clap(long, value_parser, default_value_t = 100)]
+    token_rate_limit_burst: u32,
+    /// Seconds to wait for running requests to finish when shutting down
+    #[clap(long, value_parser, default_value_t = 10)]
+    shutdown_timeout: u64,
+    /// Log level such as info or debug, RUST_LOG takes precedence
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: String,
+    /// Log in JSON format
+    #[clap(long, value_parser)]
+    log_json: bool,
+    /// Seconds between database checks for the gRPC health service
+    #[clap(long, value_parser, default_value_t = 5)]
+    health_check_interval: u64,
+    /// Offer gRPC reflection so that tools like grpcurl can explore
+    /// the services without the .proto file
+    #[clap(long, value_parser)]
+    reflection: bool,
+    /// TOML file with alert rules, see server/alert_rules.example.toml
+    #[clap(long, value_parser)]
+    alert_rules: Option<PathBuf>,
+    /// Seconds between checks whether pending alerts fire
+    #[clap(long, value_parser, default_value_t = 15)]
+    alert_evaluation_interval: u64,
+    /// URL to post notifications about alerts, new machines and mounts
+    /// to, can be given multiple times. Payloads are signed if
+    /// TEACUP_WEBHOOK_SECRET is set.
+    #[clap(long, value_parser)]
+    webhook_url: Vec<String>,
+    /// Marks a machine stale after missing this many times its
+    /// report interval
+    #[clap(long, value_parser, default_value_t = 3.)]
+    stale_after: f64,
+    /// Marks a machine offline after missing this many times its
+    /// report interval
+    #[clap(long, value_parser, default_value_t = 60.)]
+    offline_after: f64,
+    /// Seconds between checks for machines which stopped reporting
+    #[clap(long, value_parser, default_value_t = 30)]
+    status_sweep_interval: u64,
+    /// Days to keep raw cpu and memory statistics
+    #[clap(long, value_parser, default_value_t = 7)]
+    raw_retention_days: u64,
+    /// Days to keep per minute rollups of the statistics
+    #[clap(long, value_parser, default_value_t = 90)]
+    minutely_retention_days: u64,
+    /// Days to keep per hour rollups of the statistics
+    #[clap(long, value_parser, default_value_t = 730)]
+    hourly_retention_days: u64,
+    /// Seconds between rolling up and deleting expired statistics
+    #[clap(long, value_parser, default_value_t = 300)]
+    retention_interval: u64,
+    /// Batches waiting to be written before clients are told to back off
+    #[clap(long, value_parser, default_value_t = 1024)]
+    ingestion_queue_size: usize,
+    /// Tasks writing queued batches to the database concurrently
+    #[clap(long, value_parser, default_value_t = 4)]
+    ingestion_writers: usize,
+    /// Port to serve Prometheus metrics such as the queue depth on
+    #[clap(long, value_parser)]
+    metrics_port: Option<u16>,
 }
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli_config = ServerCli::parse();
+    init_logging(&cli_config.log_level, cli_config.log_json);
     let db_pw = get_db_password();
     let db_user = get_db_username();
 
+    // A reassigned host would fail the certificate check for its new id
+    // on every request
+    if cli_config.tls_client_ca.is_some()
+        && matches!(cli_config.machine_id_conflict, MachineIdConflict::Reassign)
+    {
+        return Err("--machine-id-conflict reassign can't be used with --tls-client-ca".into());
+    }
+
     let addr: SocketAddr = format!("0.0.0.0:{}", cli_config.port).parse().unwrap();
-    let sv = MetricService::new(db_user, db_pw).await;
+    let rate_limiter = RateLimiter::new(cli_config.rate_limit, cli_config.rate_limit_burst);
+    let token_rate_limiter = RateLimiter::new(
+        cli_config.token_rate_limit,
+        cli_config.token_rate_limit_burst,
+    );
+    let alert_rules = match &cli_config.alert_rules {
+        Some(path) => load_alert_rules(path).await?,
+        None => vec![],
+    };
+    info!(n_rules = alert_rules.len(), "Loaded alert rules");
+    let metrics = Arc::new(Metrics::default());
+    let sv = MetricService::new(
+        db_user,
+        db_pw,
+        cli_config.machine_id_conflict,
+        rate_limiter,
+        token_rate_limiter,
+        alert_rules,
+        cli_config.webhook_url.clone(),
+        get_webhook_secret(),
+        IngestionConfig {
+            queue_size: cli_config.ingestion_queue_size,
+            n_writers: cli_config.ingestion_writers,
+        },
+        metrics.clone(),
+    )
+    .await
+    .with_retention(RetentionPolicy {
+        raw: Duration::from_secs(cli_config.raw_retention_days * SECONDS_PER_DAY),
+        minutely: Duration::from_secs(cli_config.minutely_retention_days * SECONDS_PER_DAY),
+        hourly: Duration::from_secs(cli_config.hourly_retention_days * SECONDS_PER_DAY),
+    });
+
+    let mut server = tonic::transport::Server::builder();
+    if let (Some(tls_cert), Some(tls_key)) = (&cli_config.tls_cert, &cli_config.tls_key) {
+        let tls_config =
+            load_tls_config(tls_cert, tls_key, cli_config.tls_client_ca.as_deref()).await?;
+        server = server.tls_config(tls_config)?;
+        info!("TLS enabled");
+    }
+
+    info!(%addr, "Listening");
+
+    // On shutdown we stop accepting requests and let the running
+    // ones finish their database writes, but not forever.
+    let shutdown_rx = spawn_shutdown_listener();
+    let mut serve_shutdown_rx = shutdown_rx.clone();
+    let mut timeout_shutdown_rx = shutdown_rx.clone();
+    let shutdown_timeout = Duration::from_secs(cli_config.shutdown_timeout);
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::task::spawn(report_health(
+        health_reporter,
+        sv.clone(),
+        Duration::from_secs(cli_config.health_check_interval),
+        shutdown_rx.clone(),
+    ));
 
-    eprintln!("Listening on {}", addr);
+    let alerting_sv = sv.clone();
+    let alert_evaluation_interval = Duration::from_secs(cli_config.alert_evaluation_interval);
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(alert_evaluation_interval);
+        loop {
+            interval.tick().await;
+            alerting_sv.evaluate_alerts().await;
+        }
+    });
 
-    tonic::transport::Server::builder()
+    let sweeping_sv = sv.clone();
+    let status_sweep_interval = Duration::from_secs(cli_config.status_sweep_interval);
+    let (stale_after, offline_after) = (cli_config.stale_after, cli_config.offline_after);
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(status_sweep_interval);
+        loop {
+            interval.tick().await;
+            sweeping_sv
+                .sweep_machine_status(stale_after, offline_after)
+                .await;
+        }
+    });
+
+    let retention_sv = sv.clone();
+    let retention_interval = Duration::from_secs(cli_config.retention_interval);
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(retention_interval);
+        loop {
+            interval.tick().await;
+            retention_sv.apply_retention().await;
+        }
+    });
+
+    if let Some(metrics_port) = cli_config.metrics_port {
+        let metrics_addr: SocketAddr = format!("0.0.0.0:{}", metrics_port).parse().unwrap();
+        let metrics_shutdown_rx = shutdown_rx;
+        tokio::task::spawn(async move {
+            if let Err(e) = serve_metrics(metrics_addr, metrics, metrics_shutdown_rx).await {
+                error!(error = %e, "Metrics endpoint failed");
+            }
+        });
+    }
+
+    let reflection_service = if cli_config.reflection {
+        Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(protocol::FILE_DESCRIPTOR_SET)
+                .register_encoded_file_descriptor_set(
+                    tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+                )
+                .build()?,
+        )
+    } else {
+        None
+    };
+
+    let ingestion_sv = sv.clone();
+    let serve = server
+        .add_service(health_service)
+        .add_optional_service(reflection_service)
         .add_service(EventServiceServer::new(sv))
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, async move {
+            wait_for_shutdown(&mut serve_shutdown_rx).await;
+        });
+
+    tokio::select! {
+        result = serve => result?,
+        _ = async {
+            wait_for_shutdown(&mut timeout_shutdown_rx).await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => {
+            warn!("Timed out waiting for running requests to finish");
+        }
+    }
+
+    // SendEvents and StreamEvents only answer once their batches are
+    // stored, yet requests cut off by the timeout above leave theirs in
+    // the queue.
+    if tokio::time::timeout(shutdown_timeout, ingestion_sv.close_ingestion())
+        .await
+        .is_err()
+    {
+        warn!("Timed out writing queued events");
+    }
 
+    info!("Shut down");
     Ok(())
 }
@@ -1,79 +1,744 @@
 extern crate protocol as proto;
 
 use self::proto::{
-    event_service_server::EventService, ChangeEventBatch, InitialStateRequest, InitialStateResponse,
+    event_service_server::EventService, ChangeEventBatch, EventType, InitialStateRequest,
+    InitialStateResponse, ListMachinesResponse, StatisticsRequest, StatisticsResponse,
+    StreamEventsAck, SubscribeRequest, ToEvent,
 };
 
 #[path = "database.rs"]
 mod database;
-use database::{Database, PgDatabase};
+use database::{Database, Machine, PgDatabase};
 
-use std::sync::Arc;
+#[cfg(test)]
+#[path = "test_database.rs"]
+mod test_database;
+
+#[path = "alerting.rs"]
+mod alerting;
+use alerting::AlertEngine;
+
+#[path = "notifier.rs"]
+mod notifier;
+pub use alerting::{load_alert_rules, AlertRule};
+use notifier::{Notification, Notifier};
+
+#[path = "machine_status.rs"]
+mod machine_status;
+use machine_status::MachineStatusTracker;
+
+#[path = "metrics.rs"]
+mod metrics;
+pub use metrics::{serve_metrics, Metrics};
+
+#[path = "ingestion.rs"]
+mod ingestion;
+pub use ingestion::IngestionConfig;
+use ingestion::{EnqueueError, IngestionQueue, QUEUE_FULL_RETRY_AFTER};
+
+#[path = "retention.rs"]
+mod retention;
+pub use retention::RetentionPolicy;
+
+#[path = "tls.rs"]
+mod tls;
+pub use tls::load_tls_config;
+use tls::{verify_machine_identity, verify_peer_certs};
+
+#[path = "rate_limiter.rs"]
+mod rate_limiter;
+pub use rate_limiter::RateLimiter;
+use rate_limiter::{machine_key, token_key};
+
+#[path = "health.rs"]
+mod health;
+pub use health::report_health;
+
+#[path = "subscriptions.rs"]
+mod subscriptions;
+use subscriptions::Broadcaster;
+
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::Stream;
+use tracing::{debug, error, warn, Instrument};
+
+/// Metadata key telling clients how long to wait before retrying
+const RETRY_AFTER_MS_HEADER: &str = "retry-after-ms";
+/// Report interval of clients which don't tell theirs
+const DEFAULT_REPORT_INTERVAL: i32 = 5;
+/// Lost races against concurrent registrations before giving up
+const MAX_REGISTRATION_ATTEMPTS: usize = 3;
+/// Streamed batches being written before we stop reading the stream
+const PENDING_STREAMED_BATCHES: usize = 64;
+
+/// What to do if a second host claims an existing machine id
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum MachineIdConflict {
+    /// Refuse the initial state request of the second host
+    Reject,
+    /// Assign a fresh machine id to the second host, which is refused
+    /// together with client certificates since their common name is the
+    /// machine id the host had before
+    Reassign,
+}
 
 #[derive(Clone, Debug)]
 pub struct MetricService {
     db: Arc<dyn Database>,
+    machine_id_conflict: MachineIdConflict,
+    rate_limiter: Arc<RateLimiter>,
+    token_rate_limiter: Arc<RateLimiter>,
+    broadcaster: Broadcaster,
+    alert_engine: Arc<AlertEngine>,
+    notifier: Notifier,
+    machine_status: MachineStatusTracker,
+    retention: RetentionPolicy,
+    ingestion_queue: IngestionQueue,
+    // token hash each machine registered with, filled on demand
+    registered_tokens: Arc<Mutex<HashMap<i64, String>>>,
 }
 
 impl MetricService {
-    pub async fn new(user: String, pw: String) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        user: String,
+        pw: String,
+        machine_id_conflict: MachineIdConflict,
+        rate_limiter: RateLimiter,
+        token_rate_limiter: RateLimiter,
+        alert_rules: Vec<AlertRule>,
+        webhook_urls: Vec<String>,
+        webhook_secret: Option<String>,
+        ingestion_config: IngestionConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let address = format!("postgres://{}:{}@localhost:5432/teacup", user, pw);
-        let db = PgDatabase::new(address.as_str()).await;
-        MetricService { db: Arc::new(db) }
+        let db: Arc<dyn Database> = Arc::new(PgDatabase::new(address.as_str()).await);
+        Self::with_database(
+            db,
+            machine_id_conflict,
+            rate_limiter,
+            token_rate_limiter,
+            alert_rules,
+            webhook_urls,
+            webhook_secret,
+            ingestion_config,
+            metrics,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn with_database(
+        db: Arc<dyn Database>,
+        machine_id_conflict: MachineIdConflict,
+        rate_limiter: RateLimiter,
+        token_rate_limiter: RateLimiter,
+        alert_rules: Vec<AlertRule>,
+        webhook_urls: Vec<String>,
+        webhook_secret: Option<String>,
+        ingestion_config: IngestionConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let notifier = Notifier::new(webhook_urls, webhook_secret, db.clone());
+        let alert_engine = AlertEngine::new(alert_rules, db.clone(), notifier.clone()).await;
+        let machine_status = MachineStatusTracker::new(db.clone(), notifier.clone());
+        let ingestion_queue = IngestionQueue::new(
+            db.clone(),
+            machine_status.clone(),
+            metrics,
+            ingestion_config,
+        );
+        MetricService {
+            machine_id_conflict,
+            rate_limiter: Arc::new(rate_limiter),
+            token_rate_limiter: Arc::new(token_rate_limiter),
+            broadcaster: Broadcaster::new(),
+            alert_engine: Arc::new(alert_engine),
+            machine_status,
+            retention: RetentionPolicy::default(),
+            ingestion_queue,
+            registered_tokens: Arc::new(Mutex::new(HashMap::new())),
+            notifier,
+            db,
+        }
+    }
+
+    /// Stops accepting events and waits until the queued ones are
+    /// stored
+    pub async fn close_ingestion(&self) {
+        self.ingestion_queue.close().await;
+    }
+
+    /// Fires alerts whose conditions held long enough in the meantime
+    pub async fn evaluate_alerts(&self) {
+        self.alert_engine.evaluate_pending().await;
+    }
+
+    pub fn with_retention(self, retention: RetentionPolicy) -> Self {
+        MetricService { retention, ..self }
+    }
+
+    /// Rolls up statistics into coarser resolutions and deletes those
+    /// older than the retention policy allows
+    pub async fn apply_retention(&self) {
+        if let Err(e) = self.db.roll_up_statistics().await {
+            error!(error = %e, "Failed to roll up statistics");
+            // Deleting raw statistics which are not rolled up would
+            // lose them for good
+            return;
+        }
+
+        match self.db.delete_expired_statistics(&self.retention).await {
+            Ok(n_deleted) => debug!(n_deleted, "Deleted expired statistics"),
+            Err(e) => error!(error = %e, "Failed to delete expired statistics"),
+        }
+    }
+
+    /// Marks machines which stopped reporting as stale or offline
+    pub async fn sweep_machine_status(&self, stale_after: f64, offline_after: f64) {
+        self.machine_status.sweep(stale_after, offline_after).await;
+    }
+
+    /// Whether we can currently serve requests, i.e. reach the database.
+    pub async fn is_healthy(&self) -> bool {
+        match self.db.check_health().await {
+            Ok(_) => true,
+            Err(e) => {
+                error!(error = %e, "Database health check failed");
+                false
+            }
+        }
+    }
+
+    /// Limits how often a machine or a token may call us so that
+    /// e.g. two clients with the same machine id can't flood us.
+    #[allow(clippy::result_large_err)]
+    fn check_rate_limit<T>(
+        &self,
+        request: &tonic::Request<T>,
+        machine_id: i64,
+    ) -> Result<(), tonic::Status> {
+        self.check_rate_limit_for_token(&hash_auth_token(request.metadata()), machine_id)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_rate_limit_for_token(
+        &self,
+        token_hash: &str,
+        machine_id: i64,
+    ) -> Result<(), tonic::Status> {
+        let machine_key = machine_key(machine_id, token_hash);
+        let result = self.rate_limiter.check(&machine_key).and_then(|_| {
+            self.token_rate_limiter
+                .check(&token_key(token_hash))
+                // a rejected request doesn't count for the machine
+                .inspect_err(|_| self.rate_limiter.refund(&machine_key))
+        });
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(retry_after) => {
+                warn!(?retry_after, "Rate limit exceeded");
+                let mut status = tonic::Status::new(
+                    tonic::Code::ResourceExhausted,
+                    "Too many requests, retry later.",
+                );
+                // unwrapping is safe as a number is valid ascii
+                status.metadata_mut().insert(
+                    RETRY_AFTER_MS_HEADER,
+                    retry_after.as_millis().to_string().parse().unwrap(),
+                );
+                Err(status)
+            }
+        }
+    }
+
+    /// Only accepts requests for registered machines and only with the
+    /// token they registered with, so that e.g. a host whose
+    /// registration was rejected can't send events for the machine id
+    /// anyway
+    async fn check_registered(
+        &self,
+        token_hash: &str,
+        machine_id: i64,
+    ) -> Result<(), tonic::Status> {
+        let registered_token = self
+            .registered_tokens
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&machine_id)
+            .cloned();
+        let registered_token = match registered_token {
+            Some(registered_token) => registered_token,
+            None => match self.db.fetch_machine(machine_id).await {
+                Ok(Some(machine)) => {
+                    self.registered_tokens
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .insert(machine_id, machine.token_hash.clone());
+                    machine.token_hash
+                }
+                Ok(None) => {
+                    return Err(tonic::Status::new(
+                        tonic::Code::FailedPrecondition,
+                        "Machine is not registered, request the initial state first.",
+                    ));
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to fetch machine from database");
+                    return Err(tonic::Status::new(
+                        tonic::Code::Internal,
+                        "Failed to fetch machine from database.",
+                    ));
+                }
+            },
+        };
+
+        if registered_token != token_hash {
+            warn!("Request for a machine with another token than it registered with");
+            return Err(tonic::Status::new(
+                tonic::Code::PermissionDenied,
+                "Machine is registered with another token.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Tells clients to back off if the database can't keep up
+    fn ingestion_error(error: EnqueueError) -> tonic::Status {
+        match error {
+            EnqueueError::Full => {
+                warn!("Ingestion queue is full");
+                let mut status = tonic::Status::new(
+                    tonic::Code::ResourceExhausted,
+                    "Too many events waiting to be stored, retry later.",
+                );
+                // unwrapping is safe as a number is valid ascii
+                status.metadata_mut().insert(
+                    RETRY_AFTER_MS_HEADER,
+                    QUEUE_FULL_RETRY_AFTER
+                        .as_millis()
+                        .to_string()
+                        .parse()
+                        .unwrap(),
+                );
+                status
+            }
+            EnqueueError::Closed => {
+                tonic::Status::new(tonic::Code::Unavailable, "Event ingestion stopped.")
+            }
+        }
+    }
+
+    /// Registers the machine if unknown and returns the machine id
+    /// to be used by the client.
+    async fn register_machine(&self, machine: Machine) -> Result<i64, tonic::Status> {
+        // Concurrent requests for the same machine id are caught when
+        // saving, then we look again
+        for _ in 0..MAX_REGISTRATION_ATTEMPTS {
+            let registered_machine = match self.db.fetch_machine(machine.id).await {
+                Ok(registered_machine) => registered_machine,
+                Err(e) => {
+                    error!(error = %e, "Failed to fetch machine from database");
+                    return Err(tonic::Status::new(
+                        tonic::Code::Internal,
+                        "Failed to fetch machine from database.",
+                    ));
+                }
+            };
+
+            let (machine, is_new, saved) = match registered_machine {
+                Some(registered_machine) if !registered_machine.is_same_host(&machine) => {
+                    warn!(
+                        hostname = %machine.hostname,
+                        ip = ?machine.ip,
+                        registered_hostname = %registered_machine.hostname,
+                        registered_ip = ?registered_machine.ip,
+                        "Machine id is claimed by another host"
+                    );
+                    match self.machine_id_conflict {
+                        MachineIdConflict::Reject => {
+                            return Err(tonic::Status::new(
+                                tonic::Code::AlreadyExists,
+                                "Machine id is already used by another machine.",
+                            ));
+                        }
+                        MachineIdConflict::Reassign => {
+                            let machine = Machine {
+                                id: self.generate_machine_id().await?,
+                                ..machine.clone()
+                            };
+                            let saved = self.db.insert_machine(&machine).await;
+                            (machine, true, saved)
+                        }
+                    }
+                }
+                Some(registered_machine) => {
+                    let saved = self.db.update_machine(&machine, &registered_machine).await;
+                    (machine.clone(), false, saved)
+                }
+                None => {
+                    let saved = self.db.insert_machine(&machine).await;
+                    (machine.clone(), true, saved)
+                }
+            };
+
+            match saved {
+                Ok(true) => {
+                    self.registered_tokens
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .insert(machine.id, machine.token_hash.clone());
+                    if is_new {
+                        self.notifier.notify(Notification::MachineRegistered {
+                            machine_id: machine.id,
+                            hostname: machine.hostname.clone(),
+                            ip: machine.ip.clone(),
+                        });
+                    }
+                    return Ok(machine.id);
+                }
+                Ok(false) => continue,
+                Err(e) => {
+                    error!(error = %e, "Failed to save machine to database");
+                    return Err(tonic::Status::new(
+                        tonic::Code::Internal,
+                        "Failed to save machine to database.",
+                    ));
+                }
+            }
+        }
+
+        warn!("Machine kept changing while registering it");
+        Err(tonic::Status::new(
+            tonic::Code::Aborted,
+            "Machine is registered concurrently, retry later.",
+        ))
+    }
+
+    async fn generate_machine_id(&self) -> Result<i64, tonic::Status> {
+        loop {
+            let machine_id = rand::thread_rng().gen::<i64>();
+            match self.db.fetch_machine(machine_id).await {
+                Ok(None) => return Ok(machine_id),
+                Ok(Some(_)) => continue,
+                Err(e) => {
+                    error!(error = %e, "Failed to fetch machine from database");
+                    return Err(tonic::Status::new(
+                        tonic::Code::Internal,
+                        "Failed to fetch machine from database.",
+                    ));
+                }
+            }
+        }
     }
 }
 
+fn hash_auth_token(metadata: &tonic::metadata::MetadataMap) -> String {
+    let token = metadata
+        .get("authorization")
+        .map(|token| token.as_bytes())
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(token);
+    format!("{:x}", hasher.finalize())
+}
+
 impl tonic::transport::NamedService for MetricService {
     const NAME: &'static str = "EventService";
 }
 
 #[tonic::async_trait]
 impl EventService for MetricService {
+    #[tracing::instrument(name = "send_events", skip_all, fields(machine_id = request.get_ref().machine_id))]
     async fn send_events(
         &self,
         request: tonic::Request<ChangeEventBatch>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        verify_machine_identity(&request, request.get_ref().machine_id)?;
+        self.check_rate_limit(&request, request.get_ref().machine_id)?;
+        self.check_registered(
+            &hash_auth_token(request.metadata()),
+            request.get_ref().machine_id,
+        )
+        .await?;
+
         let batch = request.into_inner();
-        eprintln!("Got batch: {:?}", batch);
+        debug!(n_events = batch.events.len(), "Got batch");
+        // e.g. spooled batches whose stream broke after storing them
+        if self
+            .ingestion_queue
+            .is_persisted(batch.machine_id, batch.sequence)
+        {
+            debug!(sequence = batch.sequence, "Skipping batch stored already");
+            return Ok(tonic::Response::new(()));
+        }
 
-        self.db.process_event(&batch).await;
+        // Writing happens in the background, see IngestionQueue, and
+        // nobody hears of the batch before it is stored
+        let persisted = self
+            .ingestion_queue
+            .enqueue_tracked(batch.clone())
+            .map_err(Self::ingestion_error)?;
+        persisted
+            .await
+            .map_err(|_| tonic::Status::new(tonic::Code::Unavailable, "Failed to store events."))?;
+        self.alert_engine.observe(&batch).await;
+        self.notifier.notify_batch(&batch);
+        self.broadcaster.publish(batch);
 
         Ok(tonic::Response::new(()))
     }
 
+    async fn stream_events(
+        &self,
+        request: tonic::Request<tonic::Streaming<ChangeEventBatch>>,
+    ) -> Result<tonic::Response<StreamEventsAck>, tonic::Status> {
+        let peer_certs = request.peer_certs();
+        let token_hash = hash_auth_token(request.metadata());
+        let mut batches = request.into_inner();
+
+        // Batches are written while we read the next ones, the committer
+        // waits for them in order and publishes those which are stored
+        let (pending_tx, mut pending_rx) =
+            mpsc::channel::<(ChangeEventBatch, oneshot::Receiver<()>)>(PENDING_STREAMED_BATCHES);
+        let committer_sv = self.clone();
+        let committer = tokio::task::spawn(async move {
+            while let Some((batch, persisted)) = pending_rx.recv().await {
+                persisted.await.map_err(|_| {
+                    tonic::Status::new(tonic::Code::Unavailable, "Failed to store events.")
+                })?;
+                committer_sv.alert_engine.observe(&batch).await;
+                committer_sv.notifier.notify_batch(&batch);
+                committer_sv.broadcaster.publish(batch);
+            }
+            Ok::<_, tonic::Status>(())
+        });
+
+        let mut last_sequence = 0;
+        while let Some(batch) = batches.message().await? {
+            let span = tracing::info_span!("stream_events", machine_id = batch.machine_id);
+            let pending = async {
+                verify_peer_certs(peer_certs.as_deref().map(Vec::as_slice), batch.machine_id)?;
+                self.check_rate_limit_for_token(&token_hash, batch.machine_id)?;
+                self.check_registered(&token_hash, batch.machine_id).await?;
+
+                debug!(
+                    n_events = batch.events.len(),
+                    sequence = batch.sequence,
+                    "Got batch"
+                );
+                if self
+                    .ingestion_queue
+                    .is_persisted(batch.machine_id, batch.sequence)
+                {
+                    debug!(sequence = batch.sequence, "Skipping batch stored already");
+                    return Ok(None);
+                }
+                let persisted = self
+                    .ingestion_queue
+                    .enqueue_tracked(batch.clone())
+                    .map_err(Self::ingestion_error)?;
+                Ok::<_, tonic::Status>(Some(persisted))
+            }
+            .instrument(span)
+            .await?;
+
+            last_sequence = batch.sequence;
+            if let Some(persisted) = pending {
+                // the committer only stops early if writing failed
+                if pending_tx.send((batch, persisted)).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        // The ack promises that every batch of the stream is stored
+        drop(pending_tx);
+        committer.await.map_err(|e| {
+            error!(error = %e, "Committing streamed batches panicked");
+            tonic::Status::new(tonic::Code::Internal, "Failed to store events.")
+        })??;
+        Ok(tonic::Response::new(StreamEventsAck {
+            sequence: last_sequence,
+        }))
+    }
+
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<ChangeEventBatch, tonic::Status>> + Send>>;
+
+    #[tracing::instrument(name = "subscribe", skip_all, fields(machine_ids = ?request.get_ref().machine_ids))]
+    async fn subscribe(
+        &self,
+        request: tonic::Request<SubscribeRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status> {
+        let peer_certs = request.peer_certs();
+        let token_hash = hash_auth_token(request.metadata());
+        let mut request = request.into_inner();
+
+        // Without machine ids the caller watches the machines of its
+        // token, which must not end up as an empty filter matching all
+        if request.machine_ids.is_empty() {
+            request.machine_ids = match self.db.fetch_machines(&token_hash).await {
+                Ok(machines) => machines
+                    .into_iter()
+                    .map(|machine| machine.machine_id)
+                    .collect(),
+                Err(e) => {
+                    error!(error = %e, "Failed to fetch machines from database");
+                    return Err(tonic::Status::new(
+                        tonic::Code::Internal,
+                        "Failed to fetch machines from database.",
+                    ));
+                }
+            };
+            if request.machine_ids.is_empty() {
+                return Err(tonic::Status::new(
+                    tonic::Code::FailedPrecondition,
+                    "No machine is registered with this token.",
+                ));
+            }
+        }
+
+        // Only the owner of a machine may watch it
+        for &machine_id in &request.machine_ids {
+            verify_peer_certs(peer_certs.as_deref().map(Vec::as_slice), machine_id)?;
+            self.check_registered(&token_hash, machine_id).await?;
+        }
+
+        let stream = self.broadcaster.subscribe(request);
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+
+    /// Lists the machines registered with the token of the request
+    async fn list_machines(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Response<ListMachinesResponse>, tonic::Status> {
+        let token_hash = hash_auth_token(request.metadata());
+        match self.db.fetch_machines(&token_hash).await {
+            Ok(machines) => Ok(tonic::Response::new(ListMachinesResponse { machines })),
+            Err(e) => {
+                error!(error = %e, "Failed to fetch machines from database");
+                Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "Failed to fetch machines from database.",
+                ))
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "query_statistics", skip_all, fields(machine_id = request.get_ref().machine_id))]
+    async fn query_statistics(
+        &self,
+        request: tonic::Request<StatisticsRequest>,
+    ) -> Result<tonic::Response<StatisticsResponse>, tonic::Status> {
+        // Only the owner of a machine may read its statistics
+        verify_machine_identity(&request, request.get_ref().machine_id)?;
+        self.check_registered(
+            &hash_auth_token(request.metadata()),
+            request.get_ref().machine_id,
+        )
+        .await?;
+
+        let request = request.into_inner();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        let from = request.from.map_or(0, |from| from.seconds);
+        let to = request.to.map_or(now, |to| to.seconds);
+
+        let resolution = self.retention.pick_resolution(from, to, now);
+        debug!(?resolution, from, to, "Querying statistics");
+
+        let cpu = self
+            .db
+            .fetch_cpu_statistics(request.machine_id, from, to, resolution)
+            .await;
+        let memory = self
+            .db
+            .fetch_memory_statistics(request.machine_id, from, to, resolution)
+            .await;
+
+        match (cpu, memory) {
+            (Ok(cpu), Ok(memory)) => Ok(tonic::Response::new(StatisticsResponse {
+                resolution: resolution.seconds(),
+                cpu,
+                memory,
+            })),
+            (Err(e), _) | (_, Err(e)) => {
+                error!(error = %e, "Failed to fetch statistics from database");
+                Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "Failed to fetch statistics from database.",
+                ))
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "initial_state", skip_all, fields(machine_id = request.get_ref().machine_id))]
     async fn initial_state(
         &self,
         request: tonic::Request<InitialStateRequest>,
     ) -> Result<tonic::Response<InitialStateResponse>, tonic::Status> {
+        verify_machine_identity(&request, request.get_ref().machine_id)?;
+        self.check_rate_limit(&request, request.get_ref().machine_id)?;
+
+        let ip = request.remote_addr().map(|addr| addr.ip().to_string());
+        let token_hash = hash_auth_token(request.metadata());
         let payload = request.into_inner();
 
+        let system_info = payload.system_info.clone().unwrap_or_default();
+        let requested_machine_id = payload.machine_id;
+        let machine_id = self
+            .register_machine(Machine {
+                id: requested_machine_id,
+                ip,
+                hostname: system_info.hostname,
+                boot_time: system_info
+                    .boot_time
+                    .map_or(0, |boot_time| boot_time.seconds),
+                token_hash,
+                report_interval: i32::try_from(payload.report_interval)
+                    .ok()
+                    .filter(|report_interval| *report_interval > 0)
+                    .unwrap_or(DEFAULT_REPORT_INTERVAL),
+            })
+            .await?;
+        self.machine_status.mark_seen(&[machine_id]).await;
+
         // Store system info which does not change over time
         match payload.system_info {
             Some(system_info) => {
-                self.db
-                    .save_system_info(payload.machine_id, &system_info)
-                    .await;
+                self.db.save_system_info(machine_id, &system_info).await;
             }
             None => {
-                eprintln!("Initial request misses system info");
+                warn!("Initial request misses system info");
             }
         };
 
         // Store cpu info which does not change over time
         match payload.cpu_info {
             Some(cpu_info) => {
-                self.db.save_cpu_info(payload.machine_id, &cpu_info).await;
+                self.db.save_cpu_info(machine_id, &cpu_info).await;
             }
             None => {
-                eprintln!("Initial request misses cpu info");
+                warn!("Initial request misses cpu info");
             }
         };
 
         // Fetch mounts so client sends us just updates
-        let mounts = match self.db.fetch_mounts(payload.machine_id).await {
+        let mounts = match self.db.fetch_mounts(machine_id).await {
             Ok(mounts) => mounts,
             Err(e) => {
-                eprintln!("Failed to fetch mounts from database: {}", e);
+                error!(error = %e, "Failed to fetch mounts from database");
                 return Err(tonic::Status::new(
                     tonic::Code::Internal,
                     "Failed to fetch mounts from database.",
@@ -81,11 +746,24 @@ impl EventService for MetricService {
             }
         };
 
+        // Mounts are only sent again once they change, thus the alert
+        // rules need to know the stored ones
+        self.alert_engine
+            .observe(&ChangeEventBatch {
+                machine_id,
+                events: mounts
+                    .iter()
+                    .map(|mount| mount.to_change_event(EventType::Update))
+                    .collect(),
+                ..Default::default()
+            })
+            .await;
+
         // Fetch mounts so client sends us just updates
-        let network_devices = match self.db.fetch_network_devices(payload.machine_id).await {
+        let network_devices = match self.db.fetch_network_devices(machine_id).await {
             Ok(network_devices) => network_devices,
             Err(e) => {
-                eprintln!("Failed to fetch network devices from database: {}", e);
+                error!(error = %e, "Failed to fetch network devices from database");
                 return Err(tonic::Status::new(
                     tonic::Code::Internal,
                     "Failed to fetch network devices from database.",
@@ -93,9 +771,185 @@ impl EventService for MetricService {
             }
         };
 
+        // Fetch running processes so client reports those which stopped
+        let processes = match self.db.fetch_processes(machine_id).await {
+            Ok(processes) => processes,
+            Err(e) => {
+                error!(error = %e, "Failed to fetch processes from database");
+                return Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "Failed to fetch processes from database.",
+                ));
+            }
+        };
+
+        // Fetch cgroups so client sends us just updates
+        let cgroups = match self.db.fetch_cgroups(machine_id).await {
+            Ok(cgroups) => cgroups,
+            Err(e) => {
+                error!(error = %e, "Failed to fetch cgroups from database");
+                return Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "Failed to fetch cgroups from database.",
+                ));
+            }
+        };
+
+        // Fetch probe results so client reports probes it doesn't run
+        // anymore
+        let probe_results = match self.db.fetch_probe_results(machine_id).await {
+            Ok(probe_results) => probe_results,
+            Err(e) => {
+                error!(error = %e, "Failed to fetch probe results from database");
+                return Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    "Failed to fetch probe results from database.",
+                ));
+            }
+        };
+
         Ok(tonic::Response::new(InitialStateResponse {
             mounts,
             network_devices,
+            processes,
+            cgroups,
+            probe_results,
+            assigned_machine_id: if machine_id != requested_machine_id {
+                machine_id
+            } else {
+                0
+            },
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::test_database::TestDatabase;
+    use super::*;
+    use proto::{change_event::Event, ChangeEvent, CpuChangeEvent};
+    use tokio_stream::StreamExt;
+
+    async fn service(db: &Arc<TestDatabase>) -> MetricService {
+        MetricService::with_database(
+            db.clone(),
+            MachineIdConflict::Reject,
+            RateLimiter::new(0., 0),
+            RateLimiter::new(0., 0),
+            vec![],
+            vec![],
+            None,
+            IngestionConfig {
+                queue_size: 16,
+                n_writers: 1,
+            },
+            Arc::new(Metrics::default()),
+        )
+        .await
+    }
+
+    fn request<T>(message: T, token: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", token.parse().unwrap());
+        request
+    }
+
+    fn register(db: &TestDatabase, machine_id: i64, token: &str) {
+        db.machines.lock().unwrap().push(Machine {
+            id: machine_id,
+            ip: None,
+            hostname: format!("host-{}", machine_id),
+            boot_time: 0,
+            token_hash: hash_auth_token(request((), token).metadata()),
+            report_interval: DEFAULT_REPORT_INTERVAL,
+        });
+    }
+
+    fn cpu_batch(machine_id: i64) -> ChangeEventBatch {
+        ChangeEventBatch {
+            machine_id,
+            events: vec![ChangeEvent {
+                event_type: EventType::Update as i32,
+                event: Some(Event::Cpu(CpuChangeEvent {
+                    usage: 0.5,
+                    temp: 40.,
+                })),
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn subscribe(
+        service: &MetricService,
+        machine_ids: Vec<i64>,
+        token: &str,
+    ) -> Result<<MetricService as EventService>::SubscribeStream, tonic::Status> {
+        let subscription = SubscribeRequest {
+            machine_ids,
+            ..Default::default()
+        };
+        service
+            .subscribe(request(subscription, token))
+            .await
+            .map(tonic::Response::into_inner)
+    }
+
+    async fn refused_with(
+        service: &MetricService,
+        machine_ids: Vec<i64>,
+        token: &str,
+    ) -> tonic::Code {
+        match subscribe(service, machine_ids, token).await {
+            Ok(_) => panic!("Subscription was accepted"),
+            Err(e) => e.code(),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_machines_of_token_by_default() {
+        let db = Arc::new(TestDatabase::default());
+        register(&db, 1, "token-a");
+        register(&db, 2, "token-b");
+        register(&db, 3, "token-a");
+        let service = service(&db).await;
+
+        let mut stream = subscribe(&service, vec![], "token-a").await.unwrap();
+        for machine_id in [2, 1, 2, 3] {
+            service.broadcaster.publish(cpu_batch(machine_id));
+        }
+
+        assert_eq!(stream.next().await.unwrap().unwrap().machine_id, 1);
+        assert_eq!(stream.next().await.unwrap().unwrap().machine_id, 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_subscription_without_machines_of_token() {
+        let db = Arc::new(TestDatabase::default());
+        register(&db, 1, "token-a");
+        let service = service(&db).await;
+
+        assert_eq!(
+            refused_with(&service, vec![], "token-b").await,
+            tonic::Code::FailedPrecondition
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_subscription_to_machine_of_other_token() {
+        let db = Arc::new(TestDatabase::default());
+        register(&db, 1, "token-a");
+        register(&db, 2, "token-b");
+        let service = service(&db).await;
+
+        assert_eq!(
+            refused_with(&service, vec![1, 2], "token-a").await,
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            refused_with(&service, vec![4], "token-a").await,
+            tonic::Code::FailedPrecondition
+        );
+    }
+}
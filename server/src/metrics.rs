@@ -0,0 +1,97 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use tc_core::wait_for_shutdown;
+use tokio::sync::watch;
+use tracing::info;
+
+/// Counters and gauges about the server itself
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub ingestion_queue_depth: AtomicI64,
+    pub ingestion_queue_capacity: AtomicI64,
+    pub ingested_batches: AtomicU64,
+    pub rejected_batches: AtomicU64,
+    pub failed_batches: AtomicU64,
+    pub ingestion_writes: AtomicU64,
+}
+
+impl Metrics {
+    /// Prometheus text format
+    fn render(&self) -> String {
+        let metrics = [
+            (
+                "teacup_ingestion_queue_depth",
+                "gauge",
+                "Batches waiting to be written to the database",
+                self.ingestion_queue_depth.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "teacup_ingestion_queue_capacity",
+                "gauge",
+                "Batches the ingestion queue holds at most",
+                self.ingestion_queue_capacity.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "teacup_ingested_batches_total",
+                "counter",
+                "Batches written to the database",
+                self.ingested_batches.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "teacup_rejected_batches_total",
+                "counter",
+                "Batches rejected since the ingestion queue was full",
+                self.rejected_batches.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "teacup_failed_batches_total",
+                "counter",
+                "Batches lost since writing them to the database failed",
+                self.failed_batches.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "teacup_ingestion_writes_total",
+                "counter",
+                "Database writes, each covering one or more batches",
+                self.ingestion_writes.load(Ordering::Relaxed) as f64,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            // writing to a string can't fail
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
+
+/// Serves the metrics over HTTP for Prometheus to scrape
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_request| {
+                let body = metrics.render();
+                async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+            }))
+        }
+    });
+
+    info!(%addr, "Serving metrics");
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { wait_for_shutdown(&mut shutdown_rx).await })
+        .await
+}
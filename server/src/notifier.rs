@@ -0,0 +1,370 @@
+extern crate protocol as proto;
+
+use super::database::{Alert, Database};
+use hmac::{Hmac, Mac};
+use proto::{change_event::Event, ChangeEventBatch, EventType};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error, warn};
+
+/// Notifications waiting for delivery before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// Deliveries running at the same time
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+const MAX_ATTEMPTS: i32 = 5;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Header with the hex encoded HMAC-SHA256 of the body
+const SIGNATURE_HEADER: &str = "x-teacup-signature";
+
+/// What is posted as JSON to the webhooks
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    AlertFired {
+        rule: String,
+        machine_id: i64,
+        series: String,
+        value: f64,
+        threshold: f64,
+    },
+    AlertResolved {
+        rule: String,
+        machine_id: i64,
+        series: String,
+    },
+    MachineRegistered {
+        machine_id: i64,
+        hostname: String,
+        ip: Option<String>,
+    },
+    MountAdded {
+        machine_id: i64,
+        device_name: String,
+        mount_location: String,
+        fs_type: String,
+    },
+    MountRemoved {
+        machine_id: i64,
+        device_name: String,
+        mount_location: String,
+    },
+}
+
+impl Notification {
+    pub fn alert_fired(alert: &Alert) -> Self {
+        Notification::AlertFired {
+            rule: alert.rule.clone(),
+            machine_id: alert.machine_id,
+            series: alert.series.clone(),
+            value: alert.value,
+            threshold: alert.threshold,
+        }
+    }
+
+    pub fn alert_resolved(alert: &Alert) -> Self {
+        Notification::AlertResolved {
+            rule: alert.rule.clone(),
+            machine_id: alert.machine_id,
+            series: alert.series.clone(),
+        }
+    }
+}
+
+/// Posts notifications to the configured webhooks in the background
+/// so that ingestion never waits for them.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    // None if no webhooks are configured
+    tx: Option<mpsc::Sender<Notification>>,
+}
+
+impl Notifier {
+    pub fn new(webhook_urls: Vec<String>, secret: Option<String>, db: Arc<dyn Database>) -> Self {
+        if webhook_urls.is_empty() {
+            return Notifier { tx: None };
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let delivery = Delivery {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build the webhook client"),
+            webhook_urls,
+            initial_retry_delay: INITIAL_RETRY_DELAY,
+            secret,
+            db,
+        };
+        tokio::task::spawn(delivery.run(rx));
+
+        Notifier { tx: Some(tx) }
+    }
+
+    pub fn notify(&self, notification: Notification) {
+        if let Some(tx) = &self.tx {
+            if let Err(e) = tx.try_send(notification) {
+                warn!(error = %e, "Dropping notification");
+            }
+        }
+    }
+
+    /// Tells about mounts which appeared or disappeared
+    pub fn notify_batch(&self, batch: &ChangeEventBatch) {
+        if self.tx.is_none() {
+            return;
+        }
+
+        for change_event in &batch.events {
+            let mount = match &change_event.event {
+                Some(Event::Mount(mount)) => mount,
+                _ => continue,
+            };
+            if change_event.event_type == EventType::Add as i32 {
+                self.notify(Notification::MountAdded {
+                    machine_id: batch.machine_id,
+                    device_name: mount.device_name.clone(),
+                    mount_location: mount.mount_location.clone(),
+                    fs_type: mount.fs_type.clone(),
+                });
+            } else if change_event.event_type == EventType::Delete as i32 {
+                self.notify(Notification::MountRemoved {
+                    machine_id: batch.machine_id,
+                    device_name: mount.device_name.clone(),
+                    mount_location: mount.mount_location.clone(),
+                });
+            }
+        }
+    }
+}
+
+struct Delivery {
+    client: reqwest::Client,
+    webhook_urls: Vec<String>,
+    initial_retry_delay: Duration,
+    secret: Option<String>,
+    db: Arc<dyn Database>,
+}
+
+impl Delivery {
+    async fn run(self, mut rx: mpsc::Receiver<Notification>) {
+        let delivery = Arc::new(self);
+        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+
+        while let Some(notification) = rx.recv().await {
+            let payload = match serde_json::to_string(&notification) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!(error = %e, "Failed to serialize notification");
+                    continue;
+                }
+            };
+
+            for url in &delivery.webhook_urls {
+                // unwrapping is safe as the semaphore is never closed
+                let permit = semaphore.clone().acquire_owned().await.unwrap();
+                let delivery = delivery.clone();
+                let url = url.clone();
+                let payload = payload.clone();
+                tokio::task::spawn(async move {
+                    delivery.deliver(&url, &payload).await;
+                    drop(permit);
+                });
+            }
+        }
+    }
+
+    /// Posts with exponential backoff and stores the payload as dead
+    /// letter if the webhook keeps failing
+    async fn deliver(&self, url: &str, payload: &str) {
+        let mut retry_delay = self.initial_retry_delay;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let error = match self.post(url, payload).await {
+                Ok(_) => {
+                    debug!(%url, attempts, "Delivered notification");
+                    return;
+                }
+                Err(e) => e,
+            };
+
+            if attempts >= MAX_ATTEMPTS {
+                error!(%url, attempts, %error, "Giving up delivering notification");
+                if let Err(e) = self
+                    .db
+                    .save_dead_letter(url, payload, &error, attempts)
+                    .await
+                {
+                    error!(error = %e, "Failed to save dead letter to database");
+                }
+                return;
+            }
+
+            warn!(%url, attempts, %error, ?retry_delay, "Failed to deliver notification");
+            tokio::time::sleep(retry_delay).await;
+            retry_delay *= 2;
+        }
+    }
+
+    async fn post(&self, url: &str, payload: &str) -> Result<(), String> {
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string());
+
+        if let Some(secret) = &self.secret {
+            // unwrapping is safe as HMAC accepts keys of any length
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(payload.as_bytes());
+            let signature = format!("sha256={:x}", mac.finalize().into_bytes());
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Webhook responded with {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_database::TestDatabase;
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    const PAYLOAD: &str =
+        r#"{"type":"machine_registered","machine_id":1,"hostname":"host","ip":null}"#;
+    // HMAC-SHA256 of the payload with the key "secret"
+    const SIGNATURE: &str =
+        "sha256=0b8065aaa3960a4e8c2ca76c6e641ca5de85117ca8ab622785dc08ad831f9a87";
+
+    /// Signature header and body of a request the webhook got
+    type Requests = Arc<Mutex<Vec<(Option<String>, String)>>>;
+
+    /// Starts a webhook answering with the statuses in turn, 200 once
+    /// they are used up
+    fn serve_webhook(statuses: Vec<u16>) -> (String, Requests) {
+        let requests = Requests::default();
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+
+        let service_requests = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let requests = service_requests.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = requests.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let signature = request
+                            .headers()
+                            .get(SIGNATURE_HEADER)
+                            .map(|signature| signature.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push((signature, String::from_utf8(body.to_vec()).unwrap()));
+
+                        let status = statuses.lock().unwrap().next().unwrap_or(200);
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/webhook", server.local_addr());
+        tokio::task::spawn(server);
+        (url, requests)
+    }
+
+    fn delivery(url: &str, secret: Option<&str>, db: Arc<TestDatabase>) -> Delivery {
+        Delivery {
+            client: reqwest::Client::new(),
+            webhook_urls: vec![url.to_string()],
+            initial_retry_delay: Duration::from_millis(1),
+            secret: secret.map(str::to_string),
+            db,
+        }
+    }
+
+    #[test]
+    fn payload_is_tagged_with_the_type() {
+        let notification = Notification::MachineRegistered {
+            machine_id: 1,
+            hostname: "host".to_string(),
+            ip: None,
+        };
+        assert_eq!(serde_json::to_string(&notification).unwrap(), PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn signs_the_payload() {
+        let (url, requests) = serve_webhook(vec![]);
+        let db = Arc::new(TestDatabase::default());
+
+        delivery(&url, Some("secret"), db.clone())
+            .deliver(&url, PAYLOAD)
+            .await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            *requests,
+            vec![(Some(SIGNATURE.to_string()), PAYLOAD.to_string())]
+        );
+        assert!(db.dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries() {
+        let (url, requests) = serve_webhook(vec![500, 503]);
+        let db = Arc::new(TestDatabase::default());
+
+        delivery(&url, None, db.clone())
+            .deliver(&url, PAYLOAD)
+            .await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|(signature, body)| signature.is_none() && body == PAYLOAD));
+        assert!(db.dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stores_dead_letter_after_the_last_attempt() {
+        let (url, requests) = serve_webhook(vec![500; MAX_ATTEMPTS as usize]);
+        let db = Arc::new(TestDatabase::default());
+
+        delivery(&url, None, db.clone())
+            .deliver(&url, PAYLOAD)
+            .await;
+
+        assert_eq!(requests.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+        let dead_letters = db.dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        let (dead_url, payload, error, attempts) = &dead_letters[0];
+        assert_eq!(dead_url, &url);
+        assert_eq!(payload, PAYLOAD);
+        assert!(error.contains("500"), "{}", error);
+        assert_eq!(*attempts, MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of buckets after which full buckets are dropped again
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Bucket of a machine, which belongs to the token as well so that a
+/// host claiming the machine id of another with a different token only
+/// uses up its own bucket
+pub fn machine_key(machine_id: i64, token_hash: &str) -> String {
+    format!("machine:{}:{}", machine_id, token_hash)
+}
+
+/// Bucket shared by all machines of a token
+pub fn token_key(token_hash: &str) -> String {
+    format!("token:{}", token_hash)
+}
+
+/// Token bucket rate limiter allowing `requests_per_second` requests
+/// per key on average with bursts of up to `burst` requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        RateLimiter {
+            requests_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for the key or returns how long to wait
+    /// until the next request is allowed.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        // a rate of 0 disables rate limiting
+        if self.requests_per_second <= 0. {
+            return Ok(());
+        }
+
+        // A poisoned lock only means another request panicked while
+        // holding it, the buckets themselves are still usable.
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            self.drop_full_buckets(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        bucket.tokens = self.refilled_tokens(bucket, now);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            let missing_tokens = 1. - bucket.tokens;
+            Err(Duration::from_secs_f64(
+                missing_tokens / self.requests_per_second,
+            ))
+        }
+    }
+
+    /// Gives back the token taken by `check`, e.g. if a later check
+    /// rejected the request anyway
+    pub fn refund(&self, key: &str) {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.).min(self.burst);
+        }
+    }
+
+    fn refilled_tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        (bucket.tokens + elapsed * self.requests_per_second).min(self.burst)
+    }
+
+    fn drop_full_buckets(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| self.refilled_tokens(bucket, now) < self.burst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_the_cap() {
+        let limiter = RateLimiter::new(1., 3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("a", now), Ok(()));
+        }
+        assert_eq!(limiter.check_at("a", now), Err(Duration::from_secs(1)));
+        // other keys have their own bucket
+        assert_eq!(limiter.check_at("b", now), Ok(()));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(2., 2);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at("a", start), Ok(()));
+        assert_eq!(limiter.check_at("a", start), Ok(()));
+        assert_eq!(
+            limiter.check_at("a", start + Duration::from_millis(250)),
+            Err(Duration::from_millis(250))
+        );
+        assert_eq!(
+            limiter.check_at("a", start + Duration::from_millis(500)),
+            Ok(())
+        );
+
+        // a long pause doesn't save up more than the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert!(limiter.check_at("a", later).is_err());
+    }
+
+    #[test]
+    fn zero_rate_disables_the_limit() {
+        let limiter = RateLimiter::new(0., 1);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.check_at("a", now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn zero_burst_allows_single_requests() {
+        let limiter = RateLimiter::new(1., 0);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at("a", now), Ok(()));
+        assert!(limiter.check_at("a", now).is_err());
+    }
+
+    #[test]
+    fn refund_gives_back_a_token() {
+        let limiter = RateLimiter::new(1., 1);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("a", now), Ok(()));
+        limiter.refund("a");
+        assert_eq!(limiter.check_at("a", now), Ok(()));
+        assert!(limiter.check_at("a", now).is_err());
+        // never beyond the burst
+        limiter.refund("a");
+        limiter.refund("a");
+        assert_eq!(limiter.check_at("a", now), Ok(()));
+        assert!(limiter.check_at("a", now).is_err());
+    }
+
+    #[test]
+    fn machine_buckets_belong_to_the_token() {
+        let limiter = RateLimiter::new(1., 1);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(&machine_key(1, "owner"), now), Ok(()));
+        assert!(limiter.check_at(&machine_key(1, "owner"), now).is_err());
+        // another host claiming the machine id doesn't share the bucket
+        assert_eq!(limiter.check_at(&machine_key(1, "other"), now), Ok(()));
+        assert_eq!(limiter.check_at(&machine_key(2, "owner"), now), Ok(()));
+    }
+
+    #[test]
+    fn token_bucket_is_shared_by_its_machines() {
+        let limiter = RateLimiter::new(1., 2);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(&token_key("owner"), now), Ok(()));
+        assert_eq!(limiter.check_at(&token_key("owner"), now), Ok(()));
+        assert!(limiter.check_at(&token_key("owner"), now).is_err());
+        assert_eq!(limiter.check_at(&token_key("other"), now), Ok(()));
+    }
+
+    #[test]
+    fn drops_full_buckets_when_there_are_many() {
+        let limiter = RateLimiter::new(1., 1);
+        let start = Instant::now();
+        for key in 0..=MAX_IDLE_BUCKETS {
+            let _ = limiter.check_at(&key.to_string(), start);
+        }
+
+        // all buckets refilled in the meantime
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limiter.check_at("new", later), Ok(()));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+/// Resolutions statistics are stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Minutely,
+    Hourly,
+}
+
+impl Resolution {
+    /// Suffix of the tables holding the statistics
+    pub fn table_suffix(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "",
+            Resolution::Minutely => "_1m",
+            Resolution::Hourly => "_1h",
+        }
+    }
+
+    /// Seconds between two samples, 0 for raw ones
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Resolution::Raw => 0,
+            Resolution::Minutely => 60,
+            Resolution::Hourly => 3600,
+        }
+    }
+}
+
+/// Points we are willing to return for a single query
+const MAX_POINTS: i64 = 2000;
+/// Raw statistics arrive about every five seconds
+const RAW_INTERVAL_SECS: i64 = 5;
+
+/// How long statistics are kept in each resolution
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub raw: Duration,
+    pub minutely: Duration,
+    pub hourly: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        const DAY: u64 = 24 * 60 * 60;
+        RetentionPolicy {
+            raw: Duration::from_secs(7 * DAY),
+            minutely: Duration::from_secs(90 * DAY),
+            hourly: Duration::from_secs(730 * DAY),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Tables with statistics, the column telling their age and how
+    /// long they are kept
+    pub fn expirations(&self) -> [(&'static str, &'static str, Duration); 8] {
+        [
+            ("cpu_statistics", "created_at", self.raw),
+            ("memory_statistics", "created_at", self.raw),
+            ("metric_samples", "created_at", self.raw),
+            ("probe_results", "created_at", self.raw),
+            ("cpu_statistics_1m", "bucket", self.minutely),
+            ("memory_statistics_1m", "bucket", self.minutely),
+            ("cpu_statistics_1h", "bucket", self.hourly),
+            ("memory_statistics_1h", "bucket", self.hourly),
+        ]
+    }
+
+    /// Picks the finest resolution which still has data at `from` and
+    /// does not return too many points. All times are seconds since
+    /// epoch.
+    pub fn pick_resolution(&self, from: i64, to: i64, now: i64) -> Resolution {
+        let range = (to - from).max(0);
+        let age = now - from;
+
+        if age <= self.raw.as_secs() as i64 && range / RAW_INTERVAL_SECS <= MAX_POINTS {
+            Resolution::Raw
+        } else if age <= self.minutely.as_secs() as i64
+            && range / Resolution::Minutely.seconds() <= MAX_POINTS
+        {
+            Resolution::Minutely
+        } else {
+            Resolution::Hourly
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+    const HOUR: i64 = 60 * 60;
+    const DAY: i64 = 24 * HOUR;
+
+    fn pick(from: i64, to: i64) -> Resolution {
+        RetentionPolicy::default().pick_resolution(from, to, NOW)
+    }
+
+    #[test]
+    fn picks_raw_for_short_recent_ranges() {
+        assert_eq!(pick(NOW - HOUR, NOW), Resolution::Raw);
+        assert_eq!(pick(NOW - 6 * DAY - HOUR, NOW - 6 * DAY), Resolution::Raw);
+        // as many points as we return at most
+        assert_eq!(
+            pick(NOW - MAX_POINTS * RAW_INTERVAL_SECS, NOW),
+            Resolution::Raw
+        );
+        // an empty or reversed range
+        assert_eq!(pick(NOW, NOW - HOUR), Resolution::Raw);
+    }
+
+    #[test]
+    fn picks_minutely_for_too_many_raw_points() {
+        assert_eq!(
+            pick(NOW - MAX_POINTS * RAW_INTERVAL_SECS - 1, NOW),
+            Resolution::Raw
+        );
+        assert_eq!(
+            pick(
+                NOW - MAX_POINTS * RAW_INTERVAL_SECS - RAW_INTERVAL_SECS,
+                NOW
+            ),
+            Resolution::Minutely
+        );
+        assert_eq!(pick(NOW - 3 * HOUR, NOW), Resolution::Minutely);
+        assert_eq!(pick(NOW - DAY, NOW), Resolution::Minutely);
+    }
+
+    #[test]
+    fn picks_minutely_once_raw_data_expired() {
+        assert_eq!(
+            pick(NOW - 8 * DAY, NOW - 8 * DAY + HOUR),
+            Resolution::Minutely
+        );
+        // a range starting before the raw data ends
+        assert_eq!(pick(NOW - 7 * DAY - 1, NOW), Resolution::Hourly);
+    }
+
+    #[test]
+    fn picks_hourly_for_long_or_old_ranges() {
+        assert_eq!(pick(NOW - 2 * DAY, NOW), Resolution::Hourly);
+        assert_eq!(
+            pick(NOW - 100 * DAY, NOW - 100 * DAY + HOUR),
+            Resolution::Hourly
+        );
+        assert_eq!(pick(NOW - 365 * DAY, NOW), Resolution::Hourly);
+    }
+
+    #[test]
+    fn follows_the_retention_limits() {
+        let retention = RetentionPolicy {
+            raw: Duration::from_secs(DAY as u64),
+            minutely: Duration::from_secs(2 * DAY as u64),
+            hourly: Duration::from_secs(30 * DAY as u64),
+        };
+        let pick = |from, to| retention.pick_resolution(from, to, NOW);
+
+        assert_eq!(pick(NOW - HOUR, NOW), Resolution::Raw);
+        assert_eq!(pick(NOW - 36 * HOUR, NOW - 35 * HOUR), Resolution::Minutely);
+        assert_eq!(
+            pick(NOW - 3 * DAY, NOW - 3 * DAY + HOUR),
+            Resolution::Hourly
+        );
+    }
+
+    #[test]
+    fn expires_each_table_after_its_resolution() {
+        let retention = RetentionPolicy::default();
+        for (table, column, keep) in retention.expirations() {
+            let expected = if table.ends_with(Resolution::Hourly.table_suffix()) {
+                (retention.hourly, "bucket")
+            } else if table.ends_with(Resolution::Minutely.table_suffix()) {
+                (retention.minutely, "bucket")
+            } else {
+                (retention.raw, "created_at")
+            };
+            assert_eq!((keep, column), expected, "{}", table);
+        }
+        // rollups outlive what they are made of
+        assert!(retention.raw < retention.minutely);
+        assert!(retention.minutely < retention.hourly);
+    }
+}
@@ -0,0 +1,266 @@
+use protocol::{change_event::Event, ChangeEventBatch, EventKind, SubscribeRequest};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+/// Batches kept for subscribers which did not receive them yet
+const BROADCAST_CAPACITY: usize = 1024;
+/// Batches buffered per subscriber before it counts as too slow
+const SUBSCRIBER_BUFFER: usize = 64;
+
+/// Fans out accepted batches to everybody watching a machine.
+///
+/// Publishing never blocks, subscribers which can't keep up are
+/// dropped instead.
+#[derive(Clone, Debug)]
+pub struct Broadcaster {
+    tx: broadcast::Sender<Arc<ChangeEventBatch>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Broadcaster { tx }
+    }
+
+    pub fn publish(&self, batch: ChangeEventBatch) {
+        // Sending only fails if nobody is subscribed
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(Arc::new(batch));
+        }
+    }
+
+    pub fn subscribe(
+        &self,
+        request: SubscribeRequest,
+    ) -> ReceiverStream<Result<ChangeEventBatch, tonic::Status>> {
+        let filter = SubscriptionFilter::new(request);
+        let mut rx = self.tx.subscribe();
+        let (tx, subscriber_rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+
+        tokio::task::spawn(async move {
+            loop {
+                let batch = tokio::select! {
+                    batch = rx.recv() => batch,
+                    // the subscriber went away
+                    _ = tx.closed() => return,
+                };
+
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(broadcast::error::RecvError::Lagged(n_batches)) => {
+                        warn!(n_batches, "Dropping subscriber which missed batches");
+                        let _ = tx.try_send(Err(too_slow()));
+                        return;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+
+                let batch = match filter.apply(&batch) {
+                    Some(batch) => batch,
+                    None => continue,
+                };
+
+                // Keep the last slot free to tell the subscriber why
+                // we stop sending
+                if tx.capacity() <= 1 {
+                    warn!("Dropping subscriber which does not keep up");
+                    let _ = tx.try_send(Err(too_slow()));
+                    return;
+                }
+                if tx.try_send(Ok(batch)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        debug!(n_subscribers = self.tx.receiver_count(), "New subscriber");
+        ReceiverStream::new(subscriber_rx)
+    }
+}
+
+fn too_slow() -> tonic::Status {
+    tonic::Status::new(
+        tonic::Code::ResourceExhausted,
+        "Subscriber does not keep up with the events.",
+    )
+}
+
+fn get_event_kind(event: &Event) -> EventKind {
+    match event {
+        Event::Cpu(_) => EventKind::Cpu,
+        Event::Memory(_) => EventKind::Memory,
+        Event::Mount(_) => EventKind::Mount,
+        Event::NetworkDevice(_) => EventKind::NetworkDevice,
+        Event::Process(_) => EventKind::Process,
+        Event::Cgroup(_) => EventKind::Cgroup,
+        Event::Metric(_) => EventKind::Metric,
+        Event::ProbeResult(_) => EventKind::Probe,
+    }
+}
+
+/// Empty event kinds match every kind, whereas the machine ids must be
+/// given as the service resolves them for the subscriber
+struct SubscriptionFilter {
+    machine_ids: HashSet<i64>,
+    event_kinds: HashSet<i32>,
+}
+
+impl SubscriptionFilter {
+    fn new(request: SubscribeRequest) -> Self {
+        SubscriptionFilter {
+            machine_ids: request.machine_ids.into_iter().collect(),
+            event_kinds: request.event_kinds.into_iter().collect(),
+        }
+    }
+
+    /// Returns the part of the batch the subscriber is interested in
+    fn apply(&self, batch: &ChangeEventBatch) -> Option<ChangeEventBatch> {
+        if !self.machine_ids.contains(&batch.machine_id) {
+            return None;
+        }
+
+        if self.event_kinds.is_empty() {
+            return Some(batch.clone());
+        }
+
+        let events: Vec<_> = batch
+            .events
+            .iter()
+            .filter(|event| {
+                event
+                    .event
+                    .as_ref()
+                    .is_some_and(|event| self.event_kinds.contains(&(get_event_kind(event) as i32)))
+            })
+            .cloned()
+            .collect();
+
+        if events.is_empty() {
+            return None;
+        }
+
+        Some(ChangeEventBatch {
+            events,
+            machine_id: batch.machine_id,
+            sequence: batch.sequence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{ChangeEvent, CpuChangeEvent, EventType, MemoryChangeEvent};
+    use tokio_stream::StreamExt;
+
+    fn cpu_event() -> ChangeEvent {
+        ChangeEvent {
+            event_type: EventType::Update as i32,
+            event: Some(Event::Cpu(CpuChangeEvent {
+                usage: 0.5,
+                temp: 40.,
+            })),
+        }
+    }
+
+    fn memory_event() -> ChangeEvent {
+        ChangeEvent {
+            event_type: EventType::Update as i32,
+            event: Some(Event::Memory(MemoryChangeEvent::default())),
+        }
+    }
+
+    fn batch(machine_id: i64, sequence: i64, events: Vec<ChangeEvent>) -> ChangeEventBatch {
+        ChangeEventBatch {
+            machine_id,
+            sequence,
+            events,
+        }
+    }
+
+    fn request(machine_ids: Vec<i64>, event_kinds: Vec<EventKind>) -> SubscribeRequest {
+        SubscribeRequest {
+            machine_ids,
+            event_kinds: event_kinds.into_iter().map(|kind| kind as i32).collect(),
+        }
+    }
+
+    #[test]
+    fn no_machine_ids_match_nothing() {
+        let filter = SubscriptionFilter::new(request(vec![], vec![]));
+
+        assert_eq!(filter.apply(&batch(1, 1, vec![cpu_event()])), None);
+    }
+
+    #[test]
+    fn filters_by_machine() {
+        let filter = SubscriptionFilter::new(request(vec![1, 3], vec![]));
+
+        let matching = batch(3, 7, vec![cpu_event(), memory_event()]);
+        assert_eq!(filter.apply(&matching), Some(matching));
+        assert_eq!(filter.apply(&batch(2, 7, vec![cpu_event()])), None);
+    }
+
+    #[test]
+    fn filters_by_event_kind() {
+        let filter = SubscriptionFilter::new(request(vec![1], vec![EventKind::Memory]));
+
+        assert_eq!(
+            filter.apply(&batch(1, 7, vec![cpu_event(), memory_event()])),
+            Some(batch(1, 7, vec![memory_event()]))
+        );
+        assert_eq!(filter.apply(&batch(1, 8, vec![cpu_event()])), None);
+    }
+
+    #[tokio::test]
+    async fn delivers_filtered_batches() {
+        let broadcaster = Broadcaster::new();
+        let mut stream = broadcaster.subscribe(request(vec![1], vec![EventKind::Cpu]));
+
+        broadcaster.publish(batch(2, 1, vec![cpu_event()]));
+        broadcaster.publish(batch(1, 1, vec![memory_event()]));
+        broadcaster.publish(batch(1, 2, vec![cpu_event(), memory_event()]));
+
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            batch(1, 2, vec![cpu_event()])
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_subscriber_with_full_buffer() {
+        let broadcaster = Broadcaster::new();
+        let mut stream = broadcaster.subscribe(request(vec![1], vec![]));
+
+        for sequence in 0..SUBSCRIBER_BUFFER as i64 * 2 {
+            broadcaster.publish(batch(1, sequence, vec![cpu_event()]));
+        }
+
+        // The last slot of the buffer is left for the error
+        for sequence in 0..SUBSCRIBER_BUFFER as i64 - 1 {
+            assert_eq!(stream.next().await.unwrap().unwrap().sequence, sequence);
+        }
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(error.code(), tonic::Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_subscriber_missing_batches() {
+        let broadcaster = Broadcaster::new();
+        let mut stream = broadcaster.subscribe(request(vec![1], vec![]));
+
+        // The subscriber gets no chance to forward anything before the
+        // broadcast channel overflows
+        for sequence in 0..BROADCAST_CAPACITY as i64 + 1 {
+            broadcaster.publish(batch(1, sequence, vec![cpu_event()]));
+        }
+
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(error.code(), tonic::Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,243 @@
+extern crate protocol as proto;
+
+use super::database::{Alert, Database, Machine, SilentMachine};
+use super::retention::{Resolution, RetentionPolicy};
+use async_trait::async_trait;
+use proto::{
+    Cgroup, ChangeEventBatch, CpuInfo, CpuSample, MachineInfo, MachineStatus, MemorySample, Mount,
+    NetworkDevice, ProbeResult, Process, SystemInfo,
+};
+use sqlx::error::Error;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Url, payload, error and attempts of a notification given up on
+pub type DeadLetter = (String, String, String, i32);
+
+/// Database kept in memory for tests. It records what the server
+/// writes, keeps the registered `machines`, fails to write the batches
+/// of `failing_machines` and answers queries it has no data for with
+/// nothing.
+#[derive(Debug, Default)]
+pub struct TestDatabase {
+    /// Machine ids of the batches of each `process_events` call
+    pub writes: Mutex<Vec<Vec<i64>>>,
+    pub written_batches: Mutex<Vec<ChangeEventBatch>>,
+    pub failing_machines: Mutex<HashSet<i64>>,
+    pub machines: Mutex<Vec<Machine>>,
+    /// Machine ids of each `mark_machines_seen` call
+    pub seen_machines: Mutex<Vec<Vec<i64>>>,
+    pub open_alerts: Mutex<Vec<Alert>>,
+    pub resolved_alerts: Mutex<Vec<Alert>>,
+    pub recent_statistics: Mutex<Vec<(i64, ChangeEventBatch)>>,
+    pub dead_letters: Mutex<Vec<DeadLetter>>,
+}
+
+fn is_same_alert(a: &Alert, b: &Alert) -> bool {
+    a.rule == b.rule && a.machine_id == b.machine_id && a.series == b.series
+}
+
+#[async_trait]
+impl Database for TestDatabase {
+    async fn process_events(&self, event_batches: &[ChangeEventBatch]) -> Result<(), Error> {
+        let machine_ids: Vec<i64> = event_batches.iter().map(|batch| batch.machine_id).collect();
+        self.writes.lock().unwrap().push(machine_ids.clone());
+
+        let failing_machines = self.failing_machines.lock().unwrap();
+        if let Some(machine_id) = machine_ids
+            .iter()
+            .find(|machine_id| failing_machines.contains(machine_id))
+        {
+            return Err(Error::Protocol(format!(
+                "Failed to write machine {}",
+                machine_id
+            )));
+        }
+        self.written_batches
+            .lock()
+            .unwrap()
+            .extend_from_slice(event_batches);
+        Ok(())
+    }
+
+    async fn save_system_info(&self, _machine_id: i64, _system_info: &SystemInfo) {}
+
+    async fn save_cpu_info(&self, _machine_id: i64, _cpu_info: &CpuInfo) {}
+
+    async fn fetch_mounts(&self, _machine_id: i64) -> Result<Vec<Mount>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_network_devices(&self, _machine_id: i64) -> Result<Vec<NetworkDevice>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_processes(&self, _machine_id: i64) -> Result<Vec<Process>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_cgroups(&self, _machine_id: i64) -> Result<Vec<Cgroup>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_probe_results(&self, _machine_id: i64) -> Result<Vec<ProbeResult>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_machine(&self, machine_id: i64) -> Result<Option<Machine>, Error> {
+        Ok(self
+            .machines
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|machine| machine.id == machine_id)
+            .cloned())
+    }
+
+    async fn insert_machine(&self, machine: &Machine) -> Result<bool, Error> {
+        let mut machines = self.machines.lock().unwrap();
+        if machines
+            .iter()
+            .any(|registered| registered.id == machine.id)
+        {
+            return Ok(false);
+        }
+        machines.push(machine.clone());
+        Ok(true)
+    }
+
+    async fn update_machine(&self, machine: &Machine, registered: &Machine) -> Result<bool, Error> {
+        let mut machines = self.machines.lock().unwrap();
+        // Only if nobody else registered the machine in the meantime
+        match machines.iter_mut().find(|stored| {
+            stored.id == machine.id
+                && stored.token_hash == machine.token_hash
+                && stored.ip == registered.ip
+                && stored.hostname == registered.hostname
+                && stored.boot_time == registered.boot_time
+        }) {
+            Some(stored) => {
+                *stored = machine.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn fetch_machines(&self, token_hash: &str) -> Result<Vec<MachineInfo>, Error> {
+        Ok(self
+            .machines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|machine| machine.token_hash == token_hash)
+            .map(|machine| MachineInfo {
+                machine_id: machine.id,
+                hostname: machine.hostname.clone(),
+                ip: machine.ip.clone().unwrap_or_default(),
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn mark_machines_seen(
+        &self,
+        machine_ids: &[i64],
+    ) -> Result<Vec<(i64, MachineStatus)>, Error> {
+        self.seen_machines
+            .lock()
+            .unwrap()
+            .push(machine_ids.to_vec());
+        Ok(vec![])
+    }
+
+    async fn sweep_machine_status(
+        &self,
+        _stale_after: f64,
+        _offline_after: f64,
+    ) -> Result<Vec<SilentMachine>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_open_alerts(&self) -> Result<Vec<Alert>, Error> {
+        Ok(self.open_alerts.lock().unwrap().clone())
+    }
+
+    async fn open_alert(&self, alert: &Alert) -> Result<(), Error> {
+        let mut open_alerts = self.open_alerts.lock().unwrap();
+        open_alerts.retain(|open_alert| !is_same_alert(open_alert, alert));
+        open_alerts.push(alert.clone());
+        Ok(())
+    }
+
+    async fn resolve_alert(&self, alert: &Alert) -> Result<(), Error> {
+        self.open_alerts
+            .lock()
+            .unwrap()
+            .retain(|open_alert| !is_same_alert(open_alert, alert));
+        self.resolved_alerts.lock().unwrap().push(alert.clone());
+        Ok(())
+    }
+
+    async fn save_dead_letter(
+        &self,
+        url: &str,
+        payload: &str,
+        error: &str,
+        attempts: i32,
+    ) -> Result<(), Error> {
+        self.dead_letters.lock().unwrap().push((
+            url.to_string(),
+            payload.to_string(),
+            error.to_string(),
+            attempts,
+        ));
+        Ok(())
+    }
+
+    async fn roll_up_statistics(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn delete_expired_statistics(&self, _retention: &RetentionPolicy) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    async fn fetch_cpu_statistics(
+        &self,
+        _machine_id: i64,
+        _from: i64,
+        _to: i64,
+        _resolution: Resolution,
+    ) -> Result<Vec<CpuSample>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_memory_statistics(
+        &self,
+        _machine_id: i64,
+        _from: i64,
+        _to: i64,
+        _resolution: Resolution,
+    ) -> Result<Vec<MemorySample>, Error> {
+        Ok(vec![])
+    }
+
+    async fn fetch_recent_statistics(
+        &self,
+        since: i64,
+    ) -> Result<Vec<(i64, ChangeEventBatch)>, Error> {
+        Ok(self
+            .recent_statistics
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(time, _)| *time >= since)
+            .cloned()
+            .collect())
+    }
+
+    async fn check_health(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::Path;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tracing::warn;
+use x509_parser::prelude::parse_x509_certificate;
+
+pub async fn load_tls_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> Result<ServerTlsConfig, std::io::Error> {
+    let cert = tokio::fs::read(cert_path).await?;
+    let key = tokio::fs::read(key_path).await?;
+    let mut tls_config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+
+    // Setting a client CA makes client certificates mandatory
+    if let Some(client_ca_path) = client_ca_path {
+        let client_ca = tokio::fs::read(client_ca_path).await?;
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+    }
+
+    Ok(tls_config)
+}
+
+/// Ensures that a client authenticated with a certificate only sends
+/// data for the machine id in the common name of its certificate.
+///
+/// Requests without client certificate pass since mutual TLS is
+/// optional.
+#[allow(clippy::result_large_err)]
+pub fn verify_machine_identity<T>(
+    request: &tonic::Request<T>,
+    machine_id: i64,
+) -> Result<(), tonic::Status> {
+    verify_peer_certs(
+        request.peer_certs().as_deref().map(Vec::as_slice),
+        machine_id,
+    )
+}
+
+/// Same as `verify_machine_identity` for certificates taken from a
+/// request beforehand, e.g. for every message of a stream.
+#[allow(clippy::result_large_err)]
+pub fn verify_peer_certs(
+    peer_certs: Option<&[Certificate]>,
+    machine_id: i64,
+) -> Result<(), tonic::Status> {
+    let peer_certs = match peer_certs {
+        Some(peer_certs) => peer_certs,
+        None => return Ok(()),
+    };
+
+    // The first certificate is the one of the client itself
+    let common_name = peer_certs.first().and_then(|cert| {
+        let (_, cert) = parse_x509_certificate(cert.get_ref()).ok()?;
+        let common_name = cert.subject().iter_common_name().next()?;
+        common_name.as_str().ok().map(|cn| cn.to_string())
+    });
+
+    match common_name {
+        Some(common_name) if common_name == machine_id.to_string() => Ok(()),
+        Some(common_name) => {
+            warn!(%common_name, "Client certificate used for another machine");
+            Err(tonic::Status::new(
+                tonic::Code::PermissionDenied,
+                "Client certificate does not belong to this machine.",
+            ))
+        }
+        None => Err(tonic::Status::new(
+            tonic::Code::PermissionDenied,
+            "Client certificate has no common name.",
+        )),
+    }
+}
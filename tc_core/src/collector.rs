@@ -0,0 +1,272 @@
+extern crate protocol as proto;
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::{get_change_events, CollectorSettings, COLLECTION_INTERVAL};
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use tokio::time::Duration;
+use tracing::{debug, error, info};
+
+pub type CollectorError = Box<dyn Error + Send + Sync>;
+
+/// Source of change events which the collection loop runs every
+/// `interval`
+#[async_trait]
+pub trait Collector: Send {
+    /// Name used in the settings and logs such as `mounts`
+    fn name(&self) -> &str;
+
+    /// How often to collect, rounded to whole collection ticks
+    fn interval(&self) -> Duration {
+        COLLECTION_INTERVAL
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError>;
+}
+
+/// Remembers what a collector reported last, so that it only sends
+/// additions, updates and deletions
+#[derive(Debug)]
+pub struct ChangeTracker<T> {
+    previous: HashMap<String, T>,
+}
+
+impl<T: proto::ToEvent + PartialEq> ChangeTracker<T> {
+    /// Starts from what the server already knows
+    pub fn new(known: HashMap<String, T>) -> Self {
+        ChangeTracker { previous: known }
+    }
+
+    pub async fn changes(&mut self, current: HashMap<String, T>) -> Vec<proto::ChangeEvent> {
+        let events = get_change_events(&self.previous, &current).await;
+        self.previous = current;
+        events
+    }
+}
+
+struct ScheduledCollector {
+    collector: Box<dyn Collector>,
+    // runs on every n-th tick
+    every_n_ticks: u64,
+}
+
+/// The collectors the collection loop runs, minus the ones disabled
+/// in the settings
+pub struct CollectorRegistry {
+    settings: CollectorSettings,
+    collectors: Vec<ScheduledCollector>,
+    // names of every registered collector, disabled ones included
+    known_names: Vec<String>,
+    tick: u64,
+}
+
+impl CollectorRegistry {
+    pub fn new(settings: CollectorSettings) -> Self {
+        CollectorRegistry {
+            settings,
+            collectors: vec![],
+            known_names: vec![],
+            tick: 0,
+        }
+    }
+
+    pub fn register(&mut self, collector: impl Collector + 'static) {
+        let name = collector.name().to_string();
+        self.known_names.push(name.clone());
+        if self.settings.disabled.contains(&name) {
+            info!(collector = %name, "Collector disabled");
+            return;
+        }
+
+        let interval = match self.settings.intervals.get(&name) {
+            Some(secs) => Duration::from_secs(*secs),
+            None => collector.interval(),
+        };
+        let every_n_ticks =
+            (interval.as_secs_f64() / COLLECTION_INTERVAL.as_secs_f64()).round() as u64;
+        debug!(collector = %name, ?interval, "Registered collector");
+
+        self.collectors.push(ScheduledCollector {
+            collector: Box::new(collector),
+            every_n_ticks: every_n_ticks.max(1),
+        });
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.collectors
+            .iter()
+            .map(|scheduled| scheduled.collector.name())
+            .collect()
+    }
+
+    /// Names in the settings which match no registered collector, such
+    /// as `cgroup` instead of `cgroups`
+    pub fn unknown_names(&self) -> Vec<&str> {
+        let mut unknown_names: Vec<&str> = self
+            .settings
+            .disabled
+            .iter()
+            .chain(self.settings.intervals.keys())
+            .filter(|name| !self.known_names.contains(name))
+            .map(String::as_str)
+            .collect();
+        unknown_names.sort_unstable();
+        unknown_names.dedup();
+        unknown_names
+    }
+
+    /// Runs the collectors which are due in this tick at the same
+    /// time, so that a slow script or probe doesn't hold up the others.
+    /// A failing collector doesn't stop the others either.
+    pub async fn collect(&mut self) -> Vec<proto::ChangeEvent> {
+        let tick = self.tick;
+        let collected = join_all(
+            self.collectors
+                .iter_mut()
+                .filter(|scheduled| tick.is_multiple_of(scheduled.every_n_ticks))
+                .map(|scheduled| async move {
+                    let result = scheduled.collector.collect().await;
+                    if let Err(err) = &result {
+                        error!(collector = scheduled.collector.name(), error = %err, "Error collecting");
+                    }
+                    result.unwrap_or_default()
+                }),
+        )
+        .await;
+        self.tick += 1;
+        collected.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    /// Reports one event per run, named after the collector
+    struct TestCollector {
+        name: String,
+        interval: Duration,
+        // waits for this before reporting, if set
+        wait_for: Option<Arc<Notify>>,
+        // notifies this once running, if set
+        started: Option<Arc<Notify>>,
+    }
+
+    impl TestCollector {
+        fn new(name: &str) -> Self {
+            TestCollector {
+                name: name.to_string(),
+                interval: COLLECTION_INTERVAL,
+                wait_for: None,
+                started: None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Collector for TestCollector {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn interval(&self) -> Duration {
+            self.interval
+        }
+
+        async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+            if let Some(started) = &self.started {
+                started.notify_one();
+            }
+            if let Some(wait_for) = &self.wait_for {
+                wait_for.notified().await;
+            }
+            if self.name == "failing" {
+                return Err("Failed".into());
+            }
+            Ok(vec![proto::ChangeEvent {
+                event_type: proto::EventType::Update as i32,
+                event: Some(proto::change_event::Event::Metric(proto::Metric {
+                    name: self.name.clone(),
+                    ..Default::default()
+                })),
+            }])
+        }
+    }
+
+    fn names(events: &[proto::ChangeEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| match &event.event {
+                Some(proto::change_event::Event::Metric(metric)) => metric.name.as_str(),
+                _ => "",
+            })
+            .collect()
+    }
+
+    fn settings(json: &str) -> CollectorSettings {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_due_collectors() {
+        let mut registry = CollectorRegistry::new(settings(
+            r#"{"disabled": ["cgroups"], "intervals": {"mounts": 10}}"#,
+        ));
+        registry.register(TestCollector::new("cpu"));
+        registry.register(TestCollector::new("cgroups"));
+        registry.register(TestCollector::new("mounts"));
+        registry.register(TestCollector {
+            interval: COLLECTION_INTERVAL * 3,
+            ..TestCollector::new("script:raid")
+        });
+        registry.register(TestCollector::new("failing"));
+
+        assert_eq!(
+            registry.names(),
+            vec!["cpu", "mounts", "script:raid", "failing"]
+        );
+        assert_eq!(
+            names(&registry.collect().await),
+            vec!["cpu", "mounts", "script:raid"]
+        );
+        assert_eq!(names(&registry.collect().await), vec!["cpu"]);
+        assert_eq!(names(&registry.collect().await), vec!["cpu", "mounts"]);
+        assert_eq!(names(&registry.collect().await), vec!["cpu", "script:raid"]);
+    }
+
+    #[tokio::test]
+    async fn runs_collectors_at_the_same_time() {
+        // The first collector only finishes once the second one started
+        let started = Arc::new(Notify::new());
+        let mut registry = CollectorRegistry::new(CollectorSettings::default());
+        registry.register(TestCollector {
+            wait_for: Some(started.clone()),
+            ..TestCollector::new("script:slow")
+        });
+        registry.register(TestCollector {
+            started: Some(started),
+            ..TestCollector::new("cpu")
+        });
+
+        let events = tokio::time::timeout(Duration::from_secs(5), registry.collect())
+            .await
+            .expect("Collectors ran one after another");
+        assert_eq!(names(&events), vec!["script:slow", "cpu"]);
+    }
+
+    #[test]
+    fn finds_unknown_names() {
+        let mut registry = CollectorRegistry::new(settings(
+            r#"{"disabled": ["cgroup", "cpu"], "intervals": {"cgroup": 10, "script:raid": 60, "mount": 60}}"#,
+        ));
+        registry.register(TestCollector::new("cpu"));
+        registry.register(TestCollector::new("cgroups"));
+        registry.register(TestCollector::new("script:raid"));
+
+        assert_eq!(registry.unknown_names(), vec!["cgroup", "mount"]);
+    }
+}
@@ -4,12 +4,24 @@ extern crate systemstat;
 extern crate tokio;
 
 use std::collections::HashMap;
-
+use std::path::{Path, PathBuf};
+
+use crate::{
+    CgroupFilter, ChangeTracker, Collector, CollectorError, CollectorRegistry, LocalSettings,
+    MetricAggregator, MountFilter, NetworkFilter, ProbeCollector, ProcessMatcher, ScriptCollector,
+    WatchedProcess,
+};
+use async_trait::async_trait;
 use prost_types::Timestamp;
 use systemstat::Platform;
 use systemstat::System;
 use tokio::sync::mpsc;
 use tokio::time;
+use tracing::{debug, error, info, warn, Instrument};
+
+// TODO make configurable
+/// How often we collect and send events
+pub const COLLECTION_INTERVAL: time::Duration = time::Duration::from_secs(5);
 
 pub async fn get_change_events<T: proto::ToEvent + std::cmp::PartialEq>(
     prev_devices: &HashMap<String, T>,
@@ -17,134 +29,271 @@ pub async fn get_change_events<T: proto::ToEvent + std::cmp::PartialEq>(
 ) -> Vec<proto::ChangeEvent> {
     let mut events: Vec<proto::ChangeEvent> = Vec::new();
 
+    // Devices in both maps must only be looked at once
     let mut all_device_names: Vec<&String> = vec![];
     all_device_names.extend(prev_devices.keys());
-    all_device_names.extend(new_devices.keys());
+    all_device_names.extend(
+        new_devices
+            .keys()
+            .filter(|device_name| !prev_devices.contains_key(*device_name)),
+    );
 
     for device_name in all_device_names {
         let prev_device = prev_devices.get(device_name);
         let new_device = new_devices.get(device_name);
 
-        // new device found -> ADD
-        if prev_device.is_none() && new_device.is_some() {
-            events.push(new_device.unwrap().to_change_event(proto::EventType::Add));
+        match (prev_device, new_device) {
+            // new device found -> ADD
+            (None, Some(new_device)) => {
+                events.push(new_device.to_change_event(proto::EventType::Add));
+            }
+            // existing device changed -> UPDATE
+            (Some(prev_device), Some(new_device)) if prev_device != new_device => {
+                events.push(new_device.to_change_event(proto::EventType::Update));
+            }
+            // old device gone -> DELETE
+            (Some(prev_device), None) => {
+                events.push(prev_device.to_change_event(proto::EventType::Delete));
+            }
+            _ => {}
         }
+    }
 
-        // existing device changed -> UPDATE
-        if prev_device.is_some() && new_device.is_some() && prev_device != new_device {
-            events.push(
-                new_device
-                    .unwrap()
-                    .to_change_event(proto::EventType::Update),
-            );
-        }
+    events
+}
 
-        // old device gone -> DELETE
-        if prev_device.is_some() && new_device.is_none() {
-            events.push(
-                prev_device
-                    .unwrap()
-                    .to_change_event(proto::EventType::Delete),
-            );
-        }
+pub struct CpuCollector {
+    sys: System,
+}
+
+#[async_trait]
+impl Collector for CpuCollector {
+    fn name(&self) -> &str {
+        "cpu"
     }
 
-    events
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let cpu_info = get_cpu_update_event(&self.sys).await;
+        Ok(vec![proto::ChangeEvent {
+            event: Some(proto::change_event::Event::Cpu(cpu_info)),
+            event_type: proto::EventType::Update.into(),
+        }])
+    }
 }
 
-pub async fn collect_events(
-    tx: mpsc::Sender<proto::ChangeEventBatch>,
-    initial_state: proto::InitialStateResponse,
-    machine_id: i64,
-) {
-    let forever = tokio::task::spawn(async move {
-        let sys = &System::new();
-        // TODO make configurable
-        let mut interval = time::interval(time::Duration::from_secs(5));
-
-        // Get previous data so that we only send changes which happened
-        // in the meantime
-        let mut previous_mounts: HashMap<String, _> = initial_state
-            .mounts
-            .iter()
-            .map(|x| (x.device_name.clone(), x.clone()))
-            .collect();
-        let mut previous_network_devices: HashMap<String, _> = initial_state
-            .network_devices
-            .iter()
-            .map(|x| (x.name.clone(), x.clone()))
-            .collect();
+pub struct MemoryCollector {
+    sys: System,
+}
 
-        // Do a looping ... wheee
-        // Don't do that at home
-        loop {
-            interval.tick().await;
+#[async_trait]
+impl Collector for MemoryCollector {
+    fn name(&self) -> &str {
+        "memory"
+    }
 
-            let mut events: Vec<proto::ChangeEvent> = vec![];
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let ram_info = get_ram_info(&self.sys).await?;
+        Ok(vec![proto::ChangeEvent {
+            event: Some(proto::change_event::Event::Memory(ram_info)),
+            event_type: proto::EventType::Update.into(),
+        }])
+    }
+}
 
-            // cpu
-            let cpu_info = get_cpu_update_event(sys).await;
-            let cpu_change_event = proto::ChangeEvent {
-                event: Some(proto::change_event::Event::Cpu(cpu_info)),
-                event_type: proto::EventType::Update.into(),
-            };
-            events.push(cpu_change_event);
-
-            // ram
-            match get_ram_info(sys).await {
-                Ok(ram_info) => {
-                    let mem_change_event = proto::ChangeEvent {
-                        event: Some(proto::change_event::Event::Memory(ram_info)),
-                        event_type: proto::EventType::Update.into(),
-                    };
-                    events.push(mem_change_event)
-                }
-                Err(e) => {
-                    eprintln!("Error getting RAM info: {:?}", e);
-                }
-            }
+pub struct MountCollector {
+    sys: System,
+    filter: MountFilter,
+    mounts: ChangeTracker<proto::Mount>,
+}
 
-            // disk
-            match get_disk_info(sys).await {
-                Ok(mounts) => {
-                    let mount_events = get_change_events(&previous_mounts, &mounts).await;
-                    previous_mounts = mounts;
-                    events.extend(mount_events);
-                }
-                Err(err) => {
-                    eprintln!("Error getting disk info: {:?}", err);
-                }
-            }
+#[async_trait]
+impl Collector for MountCollector {
+    fn name(&self) -> &str {
+        "mounts"
+    }
 
-            // network
-            match get_network_stats(sys).await {
-                Ok(network_devices) => {
-                    let network_events =
-                        get_change_events(&previous_network_devices, &network_devices).await;
-                    events.extend(network_events);
-                    previous_network_devices = network_devices;
-                }
-                Err(err) => {
-                    eprintln!("Error getting network info: {:?}", err);
-                }
-            }
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let mounts = get_disk_info(&self.sys, &self.filter).await?;
+        Ok(self.mounts.changes(mounts).await)
+    }
+}
 
-            // Send stuff to the server
-            match tx
-                .send(proto::ChangeEventBatch { machine_id, events })
-                .await
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    eprintln!("Error sending batch of events: {:?}", e);
-                }
-            }
+pub struct NetworkCollector {
+    sys: System,
+    filter: NetworkFilter,
+    aggregated: AggregatedTraffic,
+    network_devices: ChangeTracker<proto::NetworkDevice>,
+}
+
+#[async_trait]
+impl Collector for NetworkCollector {
+    fn name(&self) -> &str {
+        "network"
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let network_devices =
+            get_network_stats(&self.sys, &self.filter, &mut self.aggregated).await?;
+        Ok(self.network_devices.changes(network_devices).await)
+    }
+}
+
+pub struct ProcessCollector {
+    watchlist: Vec<WatchedProcess>,
+    processes: ChangeTracker<proto::Process>,
+}
+
+#[async_trait]
+impl Collector for ProcessCollector {
+    fn name(&self) -> &str {
+        "processes"
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let processes = get_watched_processes(&self.watchlist).await?;
+        Ok(self.processes.changes(processes).await)
+    }
+}
+
+pub struct CgroupCollector {
+    filter: CgroupFilter,
+    cgroups: ChangeTracker<proto::Cgroup>,
+}
+
+#[async_trait]
+impl Collector for CgroupCollector {
+    fn name(&self) -> &str {
+        "cgroups"
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        // Walking the hierarchy reads many small files
+        let filter = self.filter.clone();
+        let cgroups = tokio::task::spawn_blocking(move || {
+            get_cgroup_stats(Path::new(CGROUP_V2_ROOT), &filter)
+        })
+        .await??;
+        // Only cgroups whose statistics changed are sent
+        Ok(self.cgroups.changes(cgroups).await)
+    }
+}
+
+impl CollectorRegistry {
+    /// Registers the built-in collectors. The initial state tells them
+    /// what the server knows, so that we only send changes which
+    /// happened in the meantime.
+    pub fn with_defaults(
+        initial_state: &proto::InitialStateResponse,
+        settings: &LocalSettings,
+        local_metrics: MetricAggregator,
+    ) -> Self {
+        let mut registry = CollectorRegistry::new(settings.collectors.clone());
+
+        registry.register(CpuCollector { sys: System::new() });
+        registry.register(MemoryCollector { sys: System::new() });
+        registry.register(MountCollector {
+            sys: System::new(),
+            filter: settings.mount_filter.clone(),
+            mounts: ChangeTracker::new(
+                initial_state
+                    .mounts
+                    .iter()
+                    .map(|x| (x.device_name.clone(), x.clone()))
+                    .collect(),
+            ),
+        });
+        registry.register(NetworkCollector {
+            sys: System::new(),
+            filter: settings.network_filter.clone(),
+            aggregated: AggregatedTraffic::new(
+                initial_state
+                    .network_devices
+                    .iter()
+                    .find(|x| x.name == AGGREGATED_NETWORK_DEVICE),
+            ),
+            network_devices: ChangeTracker::new(
+                initial_state
+                    .network_devices
+                    .iter()
+                    .map(|x| (x.name.clone(), x.clone()))
+                    .collect(),
+            ),
+        });
+        // Even without watched processes, so that those removed from the
+        // watchlist are reported as stopped
+        registry.register(ProcessCollector {
+            watchlist: settings.process_watchlist.clone(),
+            processes: ChangeTracker::new(
+                initial_state
+                    .processes
+                    .iter()
+                    .map(|x| (x.name.clone(), x.clone()))
+                    .collect(),
+            ),
+        });
+        registry.register(CgroupCollector {
+            filter: settings.cgroup_filter.clone(),
+            cgroups: ChangeTracker::new(
+                initial_state
+                    .cgroups
+                    .iter()
+                    .map(|x| (x.path.clone(), x.clone()))
+                    .collect(),
+            ),
+        });
+        registry.register(local_metrics);
+        for script in &settings.scripts {
+            registry.register(ScriptCollector::new(script.clone()));
+        }
+        // Even without probes, so that those removed from the settings
+        // are reported as such
+        registry.register(ProbeCollector::new(
+            settings.probes.clone(),
+            initial_state
+                .probe_results
+                .iter()
+                .map(|x| (x.name.clone(), x.clone()))
+                .collect(),
+        ));
+
+        for name in registry.unknown_names() {
+            warn!(collector = %name, "Settings refer to an unknown collector");
         }
-    });
+        registry
+    }
+}
 
-    match forever.await {
-        Ok(_) => {}
-        Err(e) => eprintln!("Error collecting events: {}", e),
+/// Runs the registered collectors every tick and sends what they
+/// found as one batch
+pub async fn collect_events(
+    tx: mpsc::Sender<proto::ChangeEventBatch>,
+    machine_id: i64,
+    mut registry: CollectorRegistry,
+) {
+    let mut interval = time::interval(COLLECTION_INTERVAL);
+    info!(collectors = ?registry.names(), "Collecting events");
+
+    loop {
+        interval.tick().await;
+
+        let events = registry
+            .collect()
+            .instrument(tracing::info_span!("collection_tick", machine_id))
+            .await;
+
+        // Send stuff to the server
+        if let Err(e) = tx
+            .send(proto::ChangeEventBatch {
+                machine_id,
+                events,
+                ..Default::default()
+            })
+            .await
+        {
+            // Nobody is listening anymore, thus we stop collecting
+            info!(error = %e, "Stopping collection as nobody receives events anymore");
+            return;
+        }
     }
 }
 
@@ -155,6 +304,7 @@ pub async fn get_initial_state(machine_id: i64) -> proto::InitialStateRequest {
         machine_id,
         system_info: Some(get_system_info(&sys).await),
         cpu_info: Some(get_cpu_info().await),
+        report_interval: COLLECTION_INTERVAL.as_secs() as i64,
     }
 }
 
@@ -163,9 +313,10 @@ async fn get_cpu_info() -> proto::CpuInfo {
     let n_logical_cpus_i64 = match i64::try_from(n_logical_cpus) {
         Ok(n) => n,
         Err(err) => {
-            eprintln!(
-                "Error converting cpu count '{}' from usize to i64: {:?}",
-                n_logical_cpus, err
+            error!(
+                n_logical_cpus,
+                error = %err,
+                "Error converting cpu count from usize to i64"
             );
             0
         }
@@ -188,35 +339,35 @@ async fn get_cpu_update_event(sys: &impl Platform) -> proto::CpuChangeEvent {
 
             match cpu.done() {
                 Ok(cpu_load) => {
-                    eprintln!(
-                        "CPU load: {}% user, {}% nice, {}% system, {}% intr, {}% idle ",
-                        cpu_load.user * 100.0,
-                        cpu_load.nice * 100.0,
-                        cpu_load.system * 100.0,
-                        cpu_load.interrupt * 100.0,
-                        cpu_load.idle * 100.0
+                    debug!(
+                        user = cpu_load.user * 100.0,
+                        nice = cpu_load.nice * 100.0,
+                        system = cpu_load.system * 100.0,
+                        interrupt = cpu_load.interrupt * 100.0,
+                        idle = cpu_load.idle * 100.0,
+                        "CPU load in %"
                     );
                     cpu_load.user
                 }
                 Err(e) => {
-                    eprintln!("Error: {}", e);
+                    warn!(error = %e, "CPU load: error");
                     0.
                 }
             }
         }
         Err(x) => {
-            eprintln!("CPU load: error: {}", x);
+            warn!(error = %x, "CPU load: error");
             0.
         }
     };
 
     let temp = match sys.cpu_temp() {
         Ok(cpu_temp) => {
-            eprintln!("CPU temp: {}", cpu_temp);
+            debug!(cpu_temp, "CPU temp");
             cpu_temp
         }
         Err(err) => {
-            eprintln!("CPU temp: error: {}", err);
+            debug!(error = %err, "CPU temp: error");
             0.
         }
     };
@@ -227,7 +378,7 @@ async fn get_cpu_update_event(sys: &impl Platform) -> proto::CpuChangeEvent {
 async fn get_ram_info(sys: &impl Platform) -> Result<proto::MemoryChangeEvent, std::io::Error> {
     match sys.memory() {
         Ok(mem) => {
-            eprintln!("Memory Total: {}, Free: {}", mem.total, mem.free);
+            debug!(total = %mem.total, free = %mem.free, "Memory");
 
             Ok(proto::MemoryChangeEvent {
                 free: u64_to_i64_or_default_and_log(mem.free.as_u64()),
@@ -235,7 +386,7 @@ async fn get_ram_info(sys: &impl Platform) -> Result<proto::MemoryChangeEvent, s
             })
         }
         Err(x) => {
-            eprintln!("Memory load: error: {}", x);
+            warn!(error = %x, "Memory load: error");
             Err(x)
         }
     }
@@ -243,24 +394,25 @@ async fn get_ram_info(sys: &impl Platform) -> Result<proto::MemoryChangeEvent, s
 
 async fn get_disk_info(
     sys: &impl Platform,
+    mount_filter: &MountFilter,
 ) -> Result<HashMap<String, proto::Mount>, std::io::Error> {
-    // We are only interested in the most common fs types.
-    // I hope I don't exclude anything important 🫣
-    const ALLOWED_MOUNT_TYPES: &[&str] = &["ext", "ntfs", "vfat", "btrfs", "xfs"];
-
     match sys.mounts() {
         Ok(mounts) => {
             let mount_vec = mounts
                 .iter()
                 .filter(|fs| {
-                    ALLOWED_MOUNT_TYPES
-                        .iter()
-                        .any(|name| fs.fs_type.as_str().starts_with(name))
+                    mount_filter.is_included(&fs.fs_type, &fs.fs_mounted_on, &fs.fs_mounted_from)
                 })
                 .map(|fs| {
-                    eprintln!(
-                        "{} -> {} ({}) {}/{} free",
-                        fs.fs_mounted_from, fs.fs_mounted_on, fs.fs_type, fs.avail, fs.total
+                    debug!(
+                        device = %fs.fs_mounted_from,
+                        mount_location = %fs.fs_mounted_on,
+                        fs_type = %fs.fs_type,
+                        free = %fs.avail,
+                        total = %fs.total,
+                        files_avail = fs.files_avail,
+                        files_total = fs.files_total,
+                        "Mount"
                     );
                     (
                         fs.fs_mounted_from.clone(),
@@ -270,6 +422,8 @@ async fn get_disk_info(
                             free: u64_to_i64_or_default_and_log(fs.avail.as_u64()),
                             total: u64_to_i64_or_default_and_log(fs.total.as_u64()),
                             fs_type: fs.fs_type.clone(),
+                            files: u64_to_i64_or_default_and_log(fs.files as u64),
+                            files_avail: u64_to_i64_or_default_and_log(fs.files_avail as u64),
                         },
                     )
                 })
@@ -277,7 +431,7 @@ async fn get_disk_info(
             Ok(mount_vec)
         }
         Err(x) => {
-            eprintln!("Disk load: error: {}", x);
+            warn!(error = %x, "Disk load: error");
             Err(x)
         }
     }
@@ -287,58 +441,343 @@ pub async fn get_system_info(sys: &impl Platform) -> proto::SystemInfo {
     let mut boot_time: i64 = 0;
     match sys.boot_time() {
         Ok(new_boot_time) => {
-            eprintln!("Boot Time: {}", new_boot_time);
+            debug!(boot_time = %new_boot_time, "Boot time");
             boot_time = new_boot_time.timestamp();
         }
         Err(x) => {
-            eprintln!("Boot Time: error: {}", x);
+            warn!(error = %x, "Boot time: error");
         }
     }
 
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    debug!(%hostname, "Hostname");
+
     proto::SystemInfo {
         boot_time: Some(Timestamp {
             seconds: boot_time,
             // nanos are a bit too much
             nanos: 0,
         }),
+        hostname,
+    }
+}
+
+/// Name of the synthetic device summing up all excluded interfaces
+const AGGREGATED_NETWORK_DEVICE: &str = "other";
+
+/// Running total of the traffic of excluded interfaces, which doesn't
+/// drop when one of them vanishes
+struct AggregatedTraffic {
+    bytes_received: i64,
+    bytes_sent: i64,
+    // counters of each interface at the last collection, `None` before
+    // the first one
+    last: Option<HashMap<String, (i64, i64)>>,
+}
+
+impl AggregatedTraffic {
+    /// Continues from what the server knows
+    fn new(known: Option<&proto::NetworkDevice>) -> Self {
+        AggregatedTraffic {
+            bytes_received: known.map_or(0, |device| device.bytes_received),
+            bytes_sent: known.map_or(0, |device| device.bytes_sent),
+            last: None,
+        }
+    }
+
+    fn update(&mut self, current: HashMap<String, (i64, i64)>) {
+        let (mut bytes_received, mut bytes_sent) = (0i64, 0i64);
+        for (name, (received, sent)) in &current {
+            let (last_received, last_sent) = self
+                .last
+                .as_ref()
+                .and_then(|last| last.get(name))
+                .copied()
+                .unwrap_or_default();
+            // A counter going down was reset, e.g. by a recreated interface
+            let delta = |current: i64, last: i64| {
+                if current >= last {
+                    current - last
+                } else {
+                    current
+                }
+            };
+            bytes_received = bytes_received.saturating_add(delta(*received, last_received));
+            bytes_sent = bytes_sent.saturating_add(delta(*sent, last_sent));
+        }
+
+        if self.last.is_none() {
+            // After a restart of the client the server may know more
+            // than the counters of the interfaces still around
+            self.bytes_received = self.bytes_received.max(bytes_received);
+            self.bytes_sent = self.bytes_sent.max(bytes_sent);
+        } else {
+            self.bytes_received = self.bytes_received.saturating_add(bytes_received);
+            self.bytes_sent = self.bytes_sent.saturating_add(bytes_sent);
+        }
+        self.last = Some(current);
     }
 }
 
 async fn get_network_stats(
     sys: &impl Platform,
+    network_filter: &NetworkFilter,
+    aggregated: &mut AggregatedTraffic,
 ) -> Result<HashMap<String, proto::NetworkDevice>, std::io::Error> {
     match sys.networks() {
         Ok(networks) => {
-            let device_stats: HashMap<_, _> = networks
-                .iter()
-                .map(|network| network.0)
-                .map(|name| {
-                    let network = sys.network_stats(name).unwrap();
-                    eprintln!(
-                        "{}: sent: {}, recv: {}",
-                        name, network.tx_bytes, network.rx_bytes
+            let mut device_stats = HashMap::new();
+            let mut excluded = HashMap::new();
+
+            for name in networks.keys() {
+                let is_included = network_filter.is_included(name);
+                if !is_included && !network_filter.aggregate_excluded {
+                    continue;
+                }
+
+                // Interfaces such as veth devices may vanish at any time
+                let network = match sys.network_stats(name) {
+                    Ok(network) => network,
+                    Err(err) => {
+                        debug!(device = %name, error = %err, "Network stats: error");
+                        continue;
+                    }
+                };
+                let device = proto::NetworkDevice {
+                    name: name.clone(),
+                    bytes_received: u64_to_i64_or_default_and_log(network.rx_bytes.as_u64()),
+                    bytes_sent: u64_to_i64_or_default_and_log(network.tx_bytes.as_u64()),
+                };
+
+                if is_included {
+                    debug!(
+                        device = %name,
+                        sent = %network.tx_bytes,
+                        received = %network.rx_bytes,
+                        "Network device"
                     );
-                    (
-                        name.clone(),
-                        proto::NetworkDevice {
-                            name: name.clone(),
-                            bytes_received: u64_to_i64_or_default_and_log(
-                                network.rx_bytes.as_u64(),
-                            ),
-                            bytes_sent: u64_to_i64_or_default_and_log(network.tx_bytes.as_u64()),
-                        },
-                    )
-                })
-                .collect();
+                    device_stats.insert(name.clone(), device);
+                } else {
+                    excluded.insert(name.clone(), (device.bytes_received, device.bytes_sent));
+                }
+            }
+
+            if network_filter.aggregate_excluded {
+                aggregated.update(excluded);
+                let aggregated_device = proto::NetworkDevice {
+                    name: AGGREGATED_NETWORK_DEVICE.to_string(),
+                    bytes_received: aggregated.bytes_received,
+                    bytes_sent: aggregated.bytes_sent,
+                };
+                debug!(
+                    device = AGGREGATED_NETWORK_DEVICE,
+                    sent = aggregated_device.bytes_sent,
+                    received = aggregated_device.bytes_received,
+                    "Network device"
+                );
+                device_stats.insert(AGGREGATED_NETWORK_DEVICE.to_string(), aggregated_device);
+            }
+
             Ok(device_stats)
         }
         Err(err) => {
-            eprintln!("Network load: error: {}", err);
+            warn!(error = %err, "Network load: error");
             Err(err)
         }
     }
 }
 
+struct RunningProcess {
+    pid: i64,
+    name: String,
+    cmdline: String,
+}
+
+fn get_running_processes() -> Result<Vec<RunningProcess>, std::io::Error> {
+    let mut processes = vec![];
+
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_string_lossy().parse::<i64>() {
+            Ok(pid) => pid,
+            // not a process directory
+            Err(_) => continue,
+        };
+
+        // Processes may vanish while we look at them, thus we just
+        // skip them if anything can't be read.
+        let name = match std::fs::read_to_string(entry.path().join("comm")) {
+            Ok(name) => name.trim_end().to_string(),
+            Err(_) => continue,
+        };
+        let cmdline = match std::fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline
+                .split(|c| *c == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(" "),
+            Err(_) => continue,
+        };
+
+        processes.push(RunningProcess { pid, name, cmdline });
+    }
+
+    Ok(processes)
+}
+
+fn is_process_name(process: &RunningProcess, name: &str) -> bool {
+    // comm is truncated to 15 characters by the kernel, thus we
+    // check the executable of the command line as well.
+    let executable = process.cmdline.split(' ').next().unwrap_or("");
+    process.name == name || Path::new(executable).file_name() == Some(name.as_ref())
+}
+
+fn read_pidfile(path: &Path) -> Option<i64> {
+    let pid = std::fs::read_to_string(path)
+        .ok()?
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+    if Path::new("/proc").join(pid.to_string()).exists() {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+async fn get_watched_processes(
+    watchlist: &[WatchedProcess],
+) -> Result<HashMap<String, proto::Process>, std::io::Error> {
+    let mut watched_processes = HashMap::new();
+    if watchlist.is_empty() {
+        return Ok(watched_processes);
+    }
+    let running_processes = get_running_processes()?;
+
+    for WatchedProcess { name, matcher } in watchlist {
+        let mut pids: Vec<i64> = match matcher {
+            ProcessMatcher::ProcessName(process_name) => running_processes
+                .iter()
+                .filter(|process| is_process_name(process, process_name))
+                .map(|process| process.pid)
+                .collect(),
+            ProcessMatcher::CmdlineRegex(regex) => running_processes
+                .iter()
+                .filter(|process| regex.0.is_match(&process.cmdline))
+                .map(|process| process.pid)
+                .collect(),
+            ProcessMatcher::Pidfile(path) => read_pidfile(path).into_iter().collect(),
+        };
+        pids.sort_unstable();
+
+        if !pids.is_empty() {
+            debug!(process = %name, ?pids, "Watched process");
+            watched_processes.insert(
+                name.clone(),
+                proto::Process {
+                    name: name.clone(),
+                    pids,
+                },
+            );
+        }
+    }
+
+    Ok(watched_processes)
+}
+
+const CGROUP_V2_ROOT: &str = "/sys/fs/cgroup";
+
+/// Blocks while reading the hierarchy, thus it runs off the runtime
+fn get_cgroup_stats(
+    root: &Path,
+    filter: &CgroupFilter,
+) -> Result<HashMap<String, proto::Cgroup>, std::io::Error> {
+    // Hosts without a unified cgroup v2 hierarchy simply have
+    // no cgroups to report.
+    if !root.join("cgroup.controllers").exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut cgroups = HashMap::new();
+    let mut directories: Vec<(PathBuf, usize)> = vec![(root.to_path_buf(), 0)];
+    while let Some((directory, depth)) = directories.pop() {
+        if depth < filter.max_depth {
+            // Cgroups come and go while we walk them
+            let entries = match std::fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry.and_then(|entry| Ok((entry.file_type()?.is_dir(), entry.path())));
+                match entry {
+                    Ok((true, path)) => directories.push((path, depth + 1)),
+                    Ok((false, _)) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        // The root is the whole machine
+        if depth == 0 {
+            continue;
+        }
+        let path = match directory.strip_prefix(root) {
+            Ok(relative) => format!("/{}", relative.display()),
+            Err(_) => continue,
+        };
+        if !filter.is_included(&path) {
+            continue;
+        }
+        let cgroup = get_cgroup(&directory, path);
+        cgroups.insert(cgroup.path.clone(), cgroup);
+    }
+
+    Ok(cgroups)
+}
+
+fn get_cgroup(directory: &Path, path: String) -> proto::Cgroup {
+    // Which files exist depends on the controllers enabled for
+    // a cgroup, thus missing values are reported as 0.
+    let read = |file: &str| std::fs::read_to_string(directory.join(file)).unwrap_or_default();
+
+    let cpu_usage_usec = read("cpu.stat")
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .unwrap_or(0);
+
+    let memory_current = read("memory.current").trim().parse::<i64>().unwrap_or(0);
+    // "max" means unlimited and is reported as 0
+    let memory_max = read("memory.max").trim().parse::<i64>().unwrap_or(0);
+
+    // io.stat has one line per device such as
+    // "8:0 rbytes=1024 wbytes=2048 rios=1 wios=2 dbytes=0 dios=0"
+    let mut io_read_bytes = 0;
+    let mut io_write_bytes = 0;
+    for field in read("io.stat").split_whitespace() {
+        if let Some((key, value)) = field.split_once('=') {
+            let value = value.parse::<i64>().unwrap_or(0);
+            match key {
+                "rbytes" => io_read_bytes += value,
+                "wbytes" => io_write_bytes += value,
+                _ => {}
+            }
+        }
+    }
+
+    proto::Cgroup {
+        path,
+        cpu_usage_usec,
+        memory_current,
+        memory_max,
+        io_read_bytes,
+        io_write_bytes,
+    }
+}
+
 fn u64_to_i64_or_default_and_log(number: u64) -> i64 {
     match i64::try_from(number) {
         Ok(number) => number,
@@ -347,8 +786,150 @@ fn u64_to_i64_or_default_and_log(number: u64) -> i64 {
             // so there is not much we can do here.
             // It is important to see in the logs how often this
             // occurs.
-            eprintln!("Failed to convert u64 {} to i64: {}", number, err);
+            warn!(number, error = %err, "Failed to convert u64 to i64");
             0
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cgroup hierarchy in a temporary directory, removed on drop
+    struct CgroupTree(PathBuf);
+
+    impl CgroupTree {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = std::env::temp_dir().join(format!("teacup-{}-{}", name, std::process::id()));
+            for (file, contents) in files {
+                let file = root.join(file);
+                std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+                std::fs::write(file, contents).unwrap();
+            }
+            CgroupTree(root)
+        }
+    }
+
+    impl Drop for CgroupTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sorted_paths(cgroups: &HashMap<String, proto::Cgroup>) -> Vec<&str> {
+        let mut paths: Vec<&str> = cgroups.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        paths
+    }
+
+    #[test]
+    fn walks_cgroups_down_to_max_depth() {
+        let tree = CgroupTree::new(
+            "cgroups-depth",
+            &[
+                ("cgroup.controllers", "cpu memory io"),
+                ("cpu.stat", "usage_usec 1000\n"),
+                ("system.slice/cpu.stat", "usage_usec 10\nuser_usec 5\n"),
+                ("system.slice/nginx.service/memory.current", "2048\n"),
+                ("system.slice/nginx.service/memory.max", "max\n"),
+                ("system.slice/nginx.service/worker/memory.current", "1024\n"),
+                (
+                    "user.slice/io.stat",
+                    "8:0 rbytes=1 wbytes=2 rios=1\n8:16 rbytes=3 wbytes=4\n",
+                ),
+            ],
+        );
+
+        let cgroups = get_cgroup_stats(&tree.0, &CgroupFilter::default()).unwrap();
+        assert_eq!(
+            sorted_paths(&cgroups),
+            vec![
+                "/system.slice",
+                "/system.slice/nginx.service",
+                "/user.slice"
+            ]
+        );
+        assert_eq!(cgroups["/system.slice"].cpu_usage_usec, 10);
+        let nginx = &cgroups["/system.slice/nginx.service"];
+        assert_eq!((nginx.memory_current, nginx.memory_max), (2048, 0));
+        let user = &cgroups["/user.slice"];
+        assert_eq!((user.io_read_bytes, user.io_write_bytes), (4, 6));
+    }
+
+    #[test]
+    fn filters_cgroups_by_path() {
+        let tree = CgroupTree::new(
+            "cgroups-filter",
+            &[
+                ("cgroup.controllers", ""),
+                ("system.slice/nginx.service/memory.current", "1"),
+                ("system.slice/cron.service/memory.current", "1"),
+                ("user.slice/memory.current", "1"),
+            ],
+        );
+        let filter: CgroupFilter =
+            serde_json::from_str(r#"{"include": ["/system.slice/*"], "exclude": ["re:cron"]}"#)
+                .unwrap();
+
+        let cgroups = get_cgroup_stats(&tree.0, &filter).unwrap();
+        assert_eq!(sorted_paths(&cgroups), vec!["/system.slice/nginx.service"]);
+    }
+
+    #[test]
+    fn no_cgroups_without_unified_hierarchy() {
+        let tree = CgroupTree::new("cgroups-v1", &[("cpu/cpu.stat", "usage_usec 10\n")]);
+        assert!(get_cgroup_stats(&tree.0, &CgroupFilter::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    fn traffic(interfaces: &[(&str, i64, i64)]) -> HashMap<String, (i64, i64)> {
+        interfaces
+            .iter()
+            .map(|(name, received, sent)| (name.to_string(), (*received, *sent)))
+            .collect()
+    }
+
+    fn total(aggregated: &AggregatedTraffic) -> (i64, i64) {
+        (aggregated.bytes_received, aggregated.bytes_sent)
+    }
+
+    #[test]
+    fn aggregated_traffic_continues_from_server() {
+        let known = proto::NetworkDevice {
+            name: AGGREGATED_NETWORK_DEVICE.to_string(),
+            bytes_received: 1000,
+            bytes_sent: 500,
+        };
+        let mut aggregated = AggregatedTraffic::new(Some(&known));
+
+        // The interfaces behind the total of the server may be gone
+        aggregated.update(traffic(&[("veth1", 100, 50)]));
+        assert_eq!(total(&aggregated), (1000, 500));
+
+        aggregated.update(traffic(&[("veth1", 150, 60)]));
+        assert_eq!(total(&aggregated), (1050, 510));
+    }
+
+    #[test]
+    fn aggregated_traffic_starts_from_counters() {
+        let mut aggregated = AggregatedTraffic::new(None);
+
+        aggregated.update(traffic(&[("veth1", 100, 50), ("veth2", 20, 10)]));
+        assert_eq!(total(&aggregated), (120, 60));
+    }
+
+    #[test]
+    fn aggregated_traffic_never_drops() {
+        let mut aggregated = AggregatedTraffic::new(None);
+        aggregated.update(traffic(&[("veth1", 100, 50), ("veth2", 20, 10)]));
+
+        // veth1 vanishes, veth2 is recreated and veth3 shows up
+        aggregated.update(traffic(&[("veth2", 5, 1), ("veth3", 30, 40)]));
+        assert_eq!(total(&aggregated), (155, 101));
+
+        aggregated.update(traffic(&[]));
+        assert_eq!(total(&aggregated), (155, 101));
+    }
+}
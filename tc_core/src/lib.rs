@@ -1,7 +1,19 @@
 extern crate protocol;
 
+mod collector;
 mod data_collection;
+mod local_metrics;
 mod local_settings;
+mod logging;
+mod probe_collector;
+mod script_collector;
+mod shutdown;
 
+pub use crate::collector::*;
 pub use crate::data_collection::*;
+pub use crate::local_metrics::*;
 pub use crate::local_settings::*;
+pub use crate::logging::*;
+pub use crate::probe_collector::*;
+pub use crate::script_collector::*;
+pub use crate::shutdown::*;
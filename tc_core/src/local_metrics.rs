@@ -0,0 +1,364 @@
+extern crate protocol as proto;
+
+use crate::{Collector, CollectorError};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Distinct metrics kept per interval, protects the client from
+/// applications generating names or labels on the fly
+pub const MAX_LOCAL_METRICS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleKind {
+    Gauge,
+    // relative change of a gauge such as `+3` or `-1`
+    GaugeDelta,
+    Counter,
+    // durations in milliseconds
+    Timer,
+}
+
+/// A single value pushed by a local application
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub kind: SampleKind,
+}
+
+#[derive(Debug)]
+pub struct StatsdParseError {
+    pub line: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for StatsdParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in line {:?}", self.reason, self.line)
+    }
+}
+
+impl std::error::Error for StatsdParseError {}
+
+/// Parses a StatsD line such as `requests:1|c|@0.5|#status:500`.
+///
+/// Supports gauges (`g`, with `+`/`-` for relative changes), counters
+/// (`c`, optionally sampled) and timers (`ms` or `h`). Tags in the
+/// DogStatsD format become labels.
+pub fn parse_statsd_line(line: &str) -> Result<Sample, StatsdParseError> {
+    let error = |reason| StatsdParseError {
+        line: line.to_string(),
+        reason,
+    };
+
+    let (name, rest) = line.split_once(':').ok_or_else(|| error("Missing value"))?;
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(error("Invalid name"));
+    }
+
+    let mut fields = rest.split('|');
+    let raw_value = fields.next().unwrap_or_default();
+    let mut value: f64 = raw_value.parse().map_err(|_| error("Invalid value"))?;
+    let kind = match fields.next() {
+        Some("g") if raw_value.starts_with(['+', '-']) => SampleKind::GaugeDelta,
+        Some("g") => SampleKind::Gauge,
+        Some("c") => SampleKind::Counter,
+        Some("ms") | Some("h") => SampleKind::Timer,
+        Some(_) => return Err(error("Unknown type")),
+        None => return Err(error("Missing type")),
+    };
+
+    let mut labels = vec![];
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            let rate: f64 = rate.parse().map_err(|_| error("Invalid sample rate"))?;
+            if rate <= 0. || rate > 1. {
+                return Err(error("Invalid sample rate"));
+            }
+            // A sampled counter stands for more increments
+            if kind == SampleKind::Counter {
+                value /= rate;
+            }
+        } else if let Some(tags) = field.strip_prefix('#') {
+            for tag in tags.split(',').filter(|tag| !tag.is_empty()) {
+                // Postgres refuses NUL in labels
+                if tag.contains(char::is_control) {
+                    return Err(error("Invalid tag"));
+                }
+                let (key, value) = tag.split_once(':').unwrap_or((tag, ""));
+                labels.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+    // The same labels in another order are the same metric
+    labels.sort();
+
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+        kind,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MetricKey {
+    name: String,
+    labels: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+enum Aggregate {
+    Gauge(f64),
+    // sum of the increments within the interval
+    Counter(f64),
+    Timer {
+        count: u64,
+        sum: f64,
+        min: f64,
+        max: f64,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Aggregates {
+    current: HashMap<MetricKey, Aggregate>,
+    // last value of each gauge which relative changes apply to
+    gauges: HashMap<MetricKey, f64>,
+}
+
+/// Collects samples of local applications between two collection
+/// ticks. Clones share the same aggregates.
+#[derive(Debug, Clone, Default)]
+pub struct MetricAggregator {
+    aggregates: Arc<Mutex<Aggregates>>,
+}
+
+impl MetricAggregator {
+    pub fn record(&self, sample: Sample) {
+        let key = MetricKey {
+            name: sample.name,
+            labels: sample.labels,
+        };
+        // unwrapping is fine as nothing panics while holding the lock
+        let mut aggregates = self.aggregates.lock().unwrap();
+        let Aggregates { current, gauges } = &mut *aggregates;
+
+        if !current.contains_key(&key) && current.len() >= MAX_LOCAL_METRICS {
+            warn!(name = %key.name, "Too many local metrics, dropping sample");
+            return;
+        }
+
+        match sample.kind {
+            SampleKind::Gauge | SampleKind::GaugeDelta => {
+                let value = match sample.kind {
+                    SampleKind::GaugeDelta => {
+                        gauges.get(&key).copied().unwrap_or_default() + sample.value
+                    }
+                    _ => sample.value,
+                };
+                if gauges.len() < MAX_LOCAL_METRICS || gauges.contains_key(&key) {
+                    gauges.insert(key.clone(), value);
+                }
+                current.insert(key, Aggregate::Gauge(value));
+            }
+            SampleKind::Counter => match current.get_mut(&key) {
+                Some(Aggregate::Counter(sum)) => *sum += sample.value,
+                _ => {
+                    current.insert(key, Aggregate::Counter(sample.value));
+                }
+            },
+            SampleKind::Timer => match current.get_mut(&key) {
+                Some(Aggregate::Timer {
+                    count,
+                    sum,
+                    min,
+                    max,
+                }) => {
+                    *count += 1;
+                    *sum += sample.value;
+                    *min = min.min(sample.value);
+                    *max = max.max(sample.value);
+                }
+                _ => {
+                    current.insert(
+                        key,
+                        Aggregate::Timer {
+                            count: 1,
+                            sum: sample.value,
+                            min: sample.value,
+                            max: sample.value,
+                        },
+                    );
+                }
+            },
+        }
+    }
+
+    /// Turns everything recorded since the last call into events.
+    /// Counters are sent as their increase within the interval, timers
+    /// as `<name>.count`, `.mean`, `.min` and `.max`.
+    pub fn drain_events(&self) -> Vec<proto::ChangeEvent> {
+        let current = std::mem::take(&mut self.aggregates.lock().unwrap().current);
+
+        let mut events = vec![];
+        let mut push = |key: &MetricKey, suffix: &str, value, unit: &str, kind| {
+            let metric = proto::Metric {
+                name: format!("{}{}", key.name, suffix),
+                labels: key.labels.iter().cloned().collect(),
+                value,
+                unit: unit.to_string(),
+                kind: kind as i32,
+            };
+            events.push(proto::ToEvent::to_change_event(
+                &metric,
+                proto::EventType::Update,
+            ));
+        };
+
+        for (key, aggregate) in current {
+            match aggregate {
+                Aggregate::Gauge(value) => push(&key, "", value, "", proto::MetricKind::Gauge),
+                Aggregate::Counter(sum) => push(&key, "", sum, "", proto::MetricKind::Counter),
+                Aggregate::Timer {
+                    count,
+                    sum,
+                    min,
+                    max,
+                } => {
+                    push(&key, ".count", count as f64, "", proto::MetricKind::Counter);
+                    push(
+                        &key,
+                        ".mean",
+                        sum / count as f64,
+                        "ms",
+                        proto::MetricKind::Gauge,
+                    );
+                    push(&key, ".min", min, "ms", proto::MetricKind::Gauge);
+                    push(&key, ".max", max, "ms", proto::MetricKind::Gauge);
+                }
+            }
+        }
+        events
+    }
+}
+
+#[async_trait]
+impl Collector for MetricAggregator {
+    fn name(&self) -> &str {
+        "local_metrics"
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        Ok(self.drain_events())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, labels: &[(&str, &str)], value: f64, kind: SampleKind) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            value,
+            kind,
+        }
+    }
+
+    #[test]
+    fn parses_sample_kinds() {
+        assert_eq!(
+            parse_statsd_line("queue:12|g").unwrap(),
+            sample("queue", &[], 12., SampleKind::Gauge)
+        );
+        assert_eq!(
+            parse_statsd_line("queue:-3|g").unwrap(),
+            sample("queue", &[], -3., SampleKind::GaugeDelta)
+        );
+        assert_eq!(
+            parse_statsd_line("queue:+3|g").unwrap(),
+            sample("queue", &[], 3., SampleKind::GaugeDelta)
+        );
+        assert_eq!(
+            parse_statsd_line("requests:1|c").unwrap(),
+            sample("requests", &[], 1., SampleKind::Counter)
+        );
+        assert_eq!(
+            parse_statsd_line("latency:2.5|ms").unwrap(),
+            sample("latency", &[], 2.5, SampleKind::Timer)
+        );
+        assert_eq!(
+            parse_statsd_line("latency:2.5|h").unwrap(),
+            sample("latency", &[], 2.5, SampleKind::Timer)
+        );
+    }
+
+    #[test]
+    fn scales_sampled_counters() {
+        assert_eq!(
+            parse_statsd_line("requests:1|c|@0.25").unwrap(),
+            sample("requests", &[], 4., SampleKind::Counter)
+        );
+        // Only counters stand for more increments
+        assert_eq!(
+            parse_statsd_line("latency:10|ms|@0.5").unwrap(),
+            sample("latency", &[], 10., SampleKind::Timer)
+        );
+    }
+
+    #[test]
+    fn sorts_tags_into_labels() {
+        assert_eq!(
+            parse_statsd_line("requests:1|c|@0.5|#status:500,method:get,cached").unwrap(),
+            sample(
+                "requests",
+                &[("cached", ""), ("method", "get"), ("status", "500")],
+                2.,
+                SampleKind::Counter
+            )
+        );
+        // Values may contain the separator of keys
+        assert_eq!(
+            parse_statsd_line("requests:1|c|#url:http://host,").unwrap(),
+            sample(
+                "requests",
+                &[("url", "http://host")],
+                1.,
+                SampleKind::Counter
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let reason = |line| parse_statsd_line(line).unwrap_err().reason;
+
+        assert_eq!(reason("requests"), "Missing value");
+        assert_eq!(reason(":1|c"), "Invalid name");
+        assert_eq!(reason("my requests:1|c"), "Invalid name");
+        assert_eq!(reason("requests\0:1|c"), "Invalid name");
+        assert_eq!(reason("requests:one|c"), "Invalid value");
+        assert_eq!(reason("requests:|c"), "Invalid value");
+        assert_eq!(reason("requests:1"), "Missing type");
+        assert_eq!(reason("requests:1|s"), "Unknown type");
+        assert_eq!(reason("requests:1|c|@0"), "Invalid sample rate");
+        assert_eq!(reason("requests:1|c|@1.5"), "Invalid sample rate");
+        assert_eq!(reason("requests:1|c|@half"), "Invalid sample rate");
+        assert_eq!(reason("requests:1|c|#status:5\u{0}00"), "Invalid tag");
+    }
+
+    #[test]
+    fn error_quotes_the_line() {
+        let error = parse_statsd_line("requests:1|s").unwrap_err();
+        assert_eq!(error.to_string(), r#"Unknown type in line "requests:1|s""#);
+    }
+}
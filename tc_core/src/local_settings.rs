@@ -2,14 +2,323 @@ extern crate machine_uid;
 extern crate xdg;
 
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::{env, fs};
+use tracing::warn;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalSettings {
     pub machine_id: i64,
+    // Processes which are reported when they start or stop running
+    #[serde(default)]
+    pub process_watchlist: Vec<WatchedProcess>,
+    // Which mounts are reported
+    #[serde(default)]
+    pub mount_filter: MountFilter,
+    // Which network interfaces are reported
+    #[serde(default)]
+    pub network_filter: NetworkFilter,
+    // Which cgroups are reported
+    #[serde(default)]
+    pub cgroup_filter: CgroupFilter,
+    // Which collectors run and how often
+    #[serde(default)]
+    pub collectors: CollectorSettings,
+    // Executables whose output is reported as metrics
+    #[serde(default)]
+    pub scripts: Vec<ScriptSettings>,
+    // Services checked from this machine
+    #[serde(default)]
+    pub probes: Vec<ProbeSettings>,
+}
+
+/// A process to watch for, reported under `name` to the server.
+///
+/// Example entry in the settings file:
+/// `{"name": "db", "process_name": "postgres"}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchedProcess {
+    pub name: String,
+    #[serde(flatten)]
+    pub matcher: ProcessMatcher,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessMatcher {
+    // Executable name as in `/proc/<pid>/comm` or the first argument
+    ProcessName(String),
+    // Regex matched against the whitespace-joined command line
+    CmdlineRegex(SettingsRegex),
+    // File containing the pid of the process such as `/run/nginx.pid`
+    Pidfile(PathBuf),
+}
+
+/// Selects the mounts to report.
+///
+/// A mount is reported if it matches every non-empty include list
+/// and none of the exclude lists. File system types are matched by
+/// prefix (`ext` matches `ext4`), paths and device names by glob
+/// such as `/var/lib/docker/*` or by regex if prefixed with `re:`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MountFilter {
+    pub include_fs_types: Vec<String>,
+    pub exclude_fs_types: Vec<String>,
+    pub include_paths: Vec<Pattern>,
+    pub exclude_paths: Vec<Pattern>,
+    pub include_devices: Vec<Pattern>,
+    pub exclude_devices: Vec<Pattern>,
+}
+
+impl Default for MountFilter {
+    fn default() -> Self {
+        MountFilter {
+            // We are only interested in the most common fs types by default.
+            include_fs_types: ["ext", "ntfs", "vfat", "btrfs", "xfs"]
+                .iter()
+                .map(|fs_type| fs_type.to_string())
+                .collect(),
+            exclude_fs_types: vec![],
+            include_paths: vec![],
+            exclude_paths: vec![],
+            include_devices: vec![],
+            exclude_devices: vec![],
+        }
+    }
+}
+
+impl MountFilter {
+    pub fn is_included(&self, fs_type: &str, path: &str, device: &str) -> bool {
+        let is_fs_type = |prefix: &String| fs_type.starts_with(prefix.as_str());
+
+        (self.include_fs_types.is_empty() || self.include_fs_types.iter().any(is_fs_type))
+            && !self.exclude_fs_types.iter().any(is_fs_type)
+            && (self.include_paths.is_empty() || matches_any_pattern(&self.include_paths, path))
+            && !matches_any_pattern(&self.exclude_paths, path)
+            && (self.include_devices.is_empty()
+                || matches_any_pattern(&self.include_devices, device))
+            && !matches_any_pattern(&self.exclude_devices, device)
+    }
+}
+
+/// Turns collectors off or changes how often they run.
+///
+/// Example: `{"disabled": ["cgroups"], "intervals": {"mounts": 60}}`
+/// with intervals in seconds, rounded to the collection interval.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CollectorSettings {
+    pub disabled: Vec<String>,
+    pub intervals: HashMap<String, u64>,
+}
+
+/// An executable which prints metrics, run every `interval` seconds
+/// and killed after `timeout` seconds. In the collector settings it
+/// is called `script:<name>`.
+///
+/// Example entry in the settings file:
+/// `{"name": "raid", "command": "/usr/local/bin/check_raid", "format": "prometheus"}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptSettings {
+    pub name: String,
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_script_interval")]
+    pub interval: u64,
+    #[serde(default = "default_script_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub format: ScriptFormat,
+}
+
+fn default_script_interval() -> u64 {
+    60
+}
+
+fn default_script_timeout() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptFormat {
+    // `[{"name": "...", "value": 1, "labels": {...}}]` or `{"name": 1}`
+    #[default]
+    Json,
+    // Prometheus text exposition format
+    Prometheus,
+}
+
+/// A service to check every `interval` seconds, reported under `name`.
+/// A probe fails if it takes longer than `timeout` seconds.
+///
+/// Example entries in the settings file:
+/// `{"name": "web", "http": {"url": "http://localhost/health", "body_regex": "ok"}}`
+/// `{"name": "db", "tcp": {"address": "localhost:5432"}}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProbeSettings {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: ProbeTarget,
+    #[serde(default = "default_probe_interval")]
+    pub interval: u64,
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: u64,
+}
+
+fn default_probe_interval() -> u64 {
+    30
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeTarget {
+    // GET request, any 2xx status passes unless `expected_status` is set
+    Http {
+        url: String,
+        #[serde(default)]
+        expected_status: Option<u16>,
+        #[serde(default)]
+        body_regex: Option<SettingsRegex>,
+    },
+    // Connect and optionally do a TLS handshake, verifying the
+    // certificate against `server_name` or the host of the address
+    Tcp {
+        address: String,
+        #[serde(default)]
+        tls: bool,
+        #[serde(default)]
+        server_name: Option<String>,
+    },
+}
+
+/// A regex compiled when loading the settings, so that an invalid one
+/// fails right away
+#[derive(Debug, Clone)]
+pub struct SettingsRegex(pub Regex);
+
+impl Serialize for SettingsRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SettingsRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let regex = String::deserialize(deserializer)?;
+        Regex::new(&regex)
+            .map(SettingsRegex)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Selects the network interfaces to report.
+///
+/// Interfaces are matched by glob such as `veth*` or by regex if
+/// prefixed with `re:`. If `aggregate_excluded` is set, the traffic
+/// of all interfaces filtered out is reported as a single device
+/// named `other` instead of being dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct NetworkFilter {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub aggregate_excluded: bool,
+}
+
+impl NetworkFilter {
+    pub fn is_included(&self, interface: &str) -> bool {
+        (self.include.is_empty() || matches_any_pattern(&self.include, interface))
+            && !matches_any_pattern(&self.exclude, interface)
+    }
+}
+
+/// Selects the cgroups to report.
+///
+/// Cgroups are reported down to `max_depth` levels below the root,
+/// which itself is left out as the cpu and memory collectors cover the
+/// whole machine. Their paths such as `/system.slice/nginx.service`
+/// are matched by glob or by regex if prefixed with `re:`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CgroupFilter {
+    pub max_depth: usize,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl Default for CgroupFilter {
+    fn default() -> Self {
+        // Slices and the services or containers within them, whereas
+        // the cgroups those create themselves are left out
+        CgroupFilter {
+            max_depth: 2,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+impl CgroupFilter {
+    pub fn is_included(&self, path: &str) -> bool {
+        (self.include.is_empty() || matches_any_pattern(&self.include, path))
+            && !matches_any_pattern(&self.exclude, path)
+    }
+}
+
+/// A glob such as `veth*`, or a regex if prefixed with `re:`.
+/// Compiled when loading the settings, so that an invalid one fails
+/// right away.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Glob(pattern) => pattern.matches(value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Pattern::Glob(pattern) => serializer.serialize_str(pattern.as_str()),
+            Pattern::Regex(regex) => serializer.serialize_str(&format!("re:{}", regex.as_str())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        match pattern.strip_prefix("re:") {
+            Some(regex) => Regex::new(regex)
+                .map(Pattern::Regex)
+                .map_err(serde::de::Error::custom),
+            None => glob::Pattern::new(&pattern)
+                .map(Pattern::Glob)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+fn matches_any_pattern(patterns: &[Pattern], value: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(value))
 }
 
 pub async fn get_settings_filepath() -> PathBuf {
@@ -25,6 +334,29 @@ pub async fn get_settings_filepath() -> PathBuf {
         .expect("Could not create the settings directory")
 }
 
+pub async fn get_spool_filepath() -> PathBuf {
+    let base_dir = xdg::BaseDirectories::with_prefix("teacup")
+        .expect("Could not determine important OS base directories which are needed");
+
+    // Events which could not be sent before shutting down are kept
+    // here until the next start.
+    base_dir
+        .place_data_file("spool.bin")
+        .expect("Could not create the data directory")
+}
+
+pub async fn get_metrics_socket_filepath() -> PathBuf {
+    let base_dir = xdg::BaseDirectories::with_prefix("teacup")
+        .expect("Could not determine important OS base directories which are needed");
+
+    // Services without a runtime directory, e.g. running as root,
+    // get the socket next to the spool file
+    base_dir
+        .place_runtime_file("metrics.sock")
+        .or_else(|_| base_dir.place_data_file("metrics.sock"))
+        .expect("Could not create the directory of the metrics socket")
+}
+
 pub async fn load_settings(config_path: &PathBuf) -> LocalSettings {
     match fs::read_to_string(config_path) {
         Ok(contents) => {
@@ -33,8 +365,15 @@ pub async fn load_settings(config_path: &PathBuf) -> LocalSettings {
         Err(_) => {
             let settings = LocalSettings {
                 machine_id: generate_machine_id().await,
+                process_watchlist: vec![],
+                mount_filter: MountFilter::default(),
+                network_filter: NetworkFilter::default(),
+                cgroup_filter: CgroupFilter::default(),
+                collectors: CollectorSettings::default(),
+                scripts: vec![],
+                probes: vec![],
             };
-            tokio::fs::write(config_path, serde_json::to_string(&settings).unwrap())
+            save_settings(config_path, &settings)
                 .await
                 .expect("Could not write config file with required settings");
 
@@ -43,6 +382,14 @@ pub async fn load_settings(config_path: &PathBuf) -> LocalSettings {
     }
 }
 
+pub async fn save_settings(
+    config_path: &PathBuf,
+    settings: &LocalSettings,
+) -> Result<(), std::io::Error> {
+    // unwrapping is safe as the settings consist of serializable types only
+    tokio::fs::write(config_path, serde_json::to_string_pretty(settings).unwrap()).await
+}
+
 async fn generate_machine_id() -> i64 {
     match machine_uid::get() {
         Ok(id) => {
@@ -56,9 +403,9 @@ async fn generate_machine_id() -> i64 {
             )
         }
         Err(err) => {
-            eprintln!(
-                "Error getting machine id, will create an artifical one: {:?}",
-                err
+            warn!(
+                error = ?err,
+                "Error getting machine id, will create an artifical one"
             );
             let mut rng = rand::thread_rng();
             rng.gen::<i64>()
@@ -69,3 +416,93 @@ async fn generate_machine_id() -> i64 {
 pub fn get_env_var_or_panic(env_name: &str) -> String {
     env::var(env_name).unwrap_or_else(|_| panic!("{} env var is not set", env_name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount_filter(json: &str) -> MountFilter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn includes_common_fs_types_by_default() {
+        let filter = MountFilter::default();
+
+        for fs_type in ["ext4", "ext2", "ntfs", "vfat", "btrfs", "xfs"] {
+            assert!(filter.is_included(fs_type, "/", "/dev/sda1"), "{}", fs_type);
+        }
+        for fs_type in ["zfs", "nfs4", "overlay", "tmpfs"] {
+            assert!(
+                !filter.is_included(fs_type, "/", "/dev/sda1"),
+                "{}",
+                fs_type
+            );
+        }
+    }
+
+    #[test]
+    fn filters_mounts_by_fs_type_prefix() {
+        let filter = mount_filter(r#"{"include_fs_types": [], "exclude_fs_types": ["tmp"]}"#);
+
+        assert!(filter.is_included("zfs", "/tank", "tank"));
+        assert!(!filter.is_included("tmpfs", "/run", "tmpfs"));
+    }
+
+    #[test]
+    fn filters_mounts_by_path_glob() {
+        let filter = mount_filter(
+            r#"{"include_paths": ["/", "/home*"], "exclude_paths": ["/home/*/.cache"]}"#,
+        );
+
+        assert!(filter.is_included("ext4", "/", "/dev/sda1"));
+        assert!(filter.is_included("ext4", "/home/alice", "/dev/sda2"));
+        assert!(!filter.is_included("ext4", "/home/alice/.cache", "/dev/sda3"));
+        assert!(!filter.is_included("ext4", "/var", "/dev/sda4"));
+        assert!(!filter.is_included("tmpfs", "/", "tmpfs"));
+    }
+
+    #[test]
+    fn filters_mounts_by_device_regex() {
+        let filter = mount_filter(
+            r#"{"include_devices": ["re:^/dev/(sd|nvme)"], "exclude_devices": ["re:p9$"]}"#,
+        );
+
+        assert!(filter.is_included("ext4", "/", "/dev/nvme0n1p1"));
+        assert!(!filter.is_included("ext4", "/boot", "/dev/nvme0n1p9"));
+        assert!(!filter.is_included("ext4", "/mnt", "/dev/loop0"));
+    }
+
+    #[test]
+    fn filters_network_interfaces() {
+        let filter: NetworkFilter =
+            serde_json::from_str(r#"{"exclude": ["lo", "veth*", "re:^br-[0-9a-f]+$"]}"#).unwrap();
+
+        assert!(filter.is_included("eth0"));
+        assert!(filter.is_included("br0"));
+        assert!(!filter.is_included("lo"));
+        assert!(!filter.is_included("veth1a2b"));
+        assert!(!filter.is_included("br-3f9c"));
+
+        let filter: NetworkFilter =
+            serde_json::from_str(r#"{"include": ["eth*", "wlan*"], "exclude": ["eth9"]}"#).unwrap();
+
+        assert!(filter.is_included("wlan0"));
+        assert!(!filter.is_included("eth9"));
+        assert!(!filter.is_included("lo"));
+    }
+
+    #[test]
+    fn refuses_invalid_patterns() {
+        assert!(serde_json::from_str::<Pattern>(r#""re:(""#).is_err());
+        assert!(serde_json::from_str::<Pattern>(r#""[""#).is_err());
+    }
+
+    #[test]
+    fn keeps_regex_prefix_when_saving_patterns() {
+        let patterns = r#"["veth*","re:^br-"]"#;
+        let parsed: Vec<Pattern> = serde_json::from_str(patterns).unwrap();
+
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), patterns);
+    }
+}
@@ -0,0 +1,16 @@
+use tracing_subscriber::EnvFilter;
+
+/// Sets up logging to stderr with the given level such as `info`,
+/// which `RUST_LOG` overrides if set.
+pub fn init_logging(log_level: &str, json: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    if json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
@@ -0,0 +1,284 @@
+extern crate protocol as proto;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{
+    ChangeTracker, Collector, CollectorError, ProbeSettings, ProbeTarget, COLLECTION_INTERVAL,
+};
+use async_trait::async_trait;
+use regex::Regex;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error};
+
+struct Probe {
+    settings: ProbeSettings,
+    // each probe has its own timeout
+    http: reqwest::Client,
+    last_start: Option<Instant>,
+}
+
+/// What a probe found out, the latency is measured by the caller
+struct ProbeOutcome {
+    status_code: i32,
+    error: Option<String>,
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn probe_http(
+    http: &reqwest::Client,
+    url: &str,
+    expected_status: Option<u16>,
+    body_regex: Option<&Regex>,
+) -> ProbeOutcome {
+    let response = match http.get(url).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return ProbeOutcome {
+                status_code: 0,
+                error: Some(e.to_string()),
+            }
+        }
+    };
+
+    let status = response.status();
+    let status_code = status.as_u16() as i32;
+    let error = match expected_status {
+        Some(expected) if status.as_u16() != expected => {
+            Some(format!("Expected status {} but got {}", expected, status))
+        }
+        None if !status.is_success() => Some(format!("Got status {}", status)),
+        _ => None,
+    };
+    if error.is_some() {
+        return ProbeOutcome { status_code, error };
+    }
+
+    // The latency covers the whole body either way
+    let error = match response.text().await {
+        Ok(body) => match body_regex {
+            Some(regex) if !regex.is_match(&body) => {
+                Some(format!("Body does not match {:?}", regex.as_str()))
+            }
+            _ => None,
+        },
+        Err(e) => Some(e.to_string()),
+    };
+    ProbeOutcome { status_code, error }
+}
+
+async fn probe_tcp(
+    tls: Option<&TlsConnector>,
+    address: &str,
+    server_name: Option<&str>,
+) -> Result<(), String> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let Some(tls) = tls {
+        // "example.com:443" or "[::1]:443"
+        let host = server_name.unwrap_or_else(|| {
+            address
+                .rsplit_once(':')
+                .map_or(address, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+        });
+        let server_name =
+            ServerName::try_from(host).map_err(|_| format!("Invalid server name {:?}", host))?;
+        tls.connect(server_name, stream)
+            .await
+            .map_err(|e| format!("TLS handshake failed: {}", e))?;
+    }
+    Ok(())
+}
+
+async fn run_probe(
+    settings: ProbeSettings,
+    http: reqwest::Client,
+    tls: TlsConnector,
+) -> proto::ProbeResult {
+    let start = Instant::now();
+    let (target, outcome) = match &settings.target {
+        ProbeTarget::Http {
+            url,
+            expected_status,
+            body_regex,
+        } => (
+            url.clone(),
+            tokio::time::timeout(
+                Duration::from_secs(settings.timeout),
+                probe_http(
+                    &http,
+                    url,
+                    *expected_status,
+                    body_regex.as_ref().map(|regex| &regex.0),
+                ),
+            )
+            .await,
+        ),
+        ProbeTarget::Tcp {
+            address,
+            tls: use_tls,
+            server_name,
+        } => (
+            address.clone(),
+            tokio::time::timeout(Duration::from_secs(settings.timeout), async {
+                let tls = use_tls.then_some(&tls);
+                ProbeOutcome {
+                    status_code: 0,
+                    error: probe_tcp(tls, address, server_name.as_deref()).await.err(),
+                }
+            })
+            .await,
+        ),
+    };
+    let latency = start.elapsed();
+
+    let outcome = outcome.unwrap_or_else(|_| ProbeOutcome {
+        status_code: 0,
+        error: Some(format!("Timed out after {:?}", latency)),
+    });
+    debug!(probe = %settings.name, ?latency, error = ?outcome.error, "Probed");
+
+    proto::ProbeResult {
+        name: settings.name,
+        target,
+        success: outcome.error.is_none(),
+        latency_ms: latency.as_secs_f64() * 1000.,
+        status_code: outcome.status_code,
+        error: outcome.error.unwrap_or_default(),
+    }
+}
+
+/// Checks services from this machine. Probes run concurrently in the
+/// background and their results are sent with the next tick, only if
+/// they changed.
+pub struct ProbeCollector {
+    probes: Vec<Probe>,
+    tls: TlsConnector,
+    run: Option<JoinHandle<Vec<proto::ProbeResult>>>,
+    // latest result of every probe, also of those not run lately
+    latest: HashMap<String, proto::ProbeResult>,
+    results: ChangeTracker<proto::ProbeResult>,
+}
+
+impl ProbeCollector {
+    /// Starts from the latest results the server knows
+    pub fn new(settings: Vec<ProbeSettings>, known: HashMap<String, proto::ProbeResult>) -> Self {
+        let probes = settings
+            .into_iter()
+            .map(|settings| {
+                // An expected 3xx status must not be followed
+                let redirect = match &settings.target {
+                    ProbeTarget::Http {
+                        expected_status: Some(_),
+                        ..
+                    } => reqwest::redirect::Policy::none(),
+                    _ => reqwest::redirect::Policy::default(),
+                };
+                // fails like reqwest::Client::new() only without a TLS backend
+                let http = reqwest::Client::builder()
+                    .redirect(redirect)
+                    .build()
+                    .expect("Failed to create HTTP client");
+                Probe {
+                    settings,
+                    http,
+                    last_start: None,
+                }
+            })
+            .collect();
+
+        ProbeCollector {
+            probes,
+            tls: tls_connector(),
+            run: None,
+            latest: HashMap::new(),
+            results: ChangeTracker::new(known),
+        }
+    }
+}
+
+#[async_trait]
+impl Collector for ProbeCollector {
+    fn name(&self) -> &str {
+        "probes"
+    }
+
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let mut events = vec![];
+        if let Some(run) = self.run.take() {
+            if !run.is_finished() {
+                self.run = Some(run);
+                return Ok(events);
+            }
+            for result in run.await? {
+                self.latest.insert(result.name.clone(), result);
+            }
+            events = self.results.changes(self.latest.clone()).await;
+        } else if self.probes.is_empty() {
+            // Nothing ever runs, yet the probes which were removed from
+            // the settings need to be reported
+            events = self.results.changes(HashMap::new()).await;
+        }
+
+        let mut due = vec![];
+        for probe in &mut self.probes {
+            // Ticks jitter a bit, half a tick early is on time
+            let interval = Duration::from_secs(probe.settings.interval);
+            if probe
+                .last_start
+                .is_none_or(|last_start| last_start.elapsed() + COLLECTION_INTERVAL / 2 >= interval)
+            {
+                probe.last_start = Some(Instant::now());
+                due.push(tokio::task::spawn(run_probe(
+                    probe.settings.clone(),
+                    probe.http.clone(),
+                    self.tls.clone(),
+                )));
+            }
+        }
+        if !due.is_empty() {
+            self.run = Some(tokio::task::spawn(async move {
+                let mut results = vec![];
+                for probe in due {
+                    match probe.await {
+                        Ok(result) => results.push(result),
+                        Err(err) => error!(error = %err, "Probe panicked"),
+                    }
+                }
+                results
+            }));
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for ProbeCollector {
+    fn drop(&mut self) {
+        if let Some(run) = &self.run {
+            run.abort();
+        }
+    }
+}
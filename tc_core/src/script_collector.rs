@@ -0,0 +1,474 @@
+extern crate protocol as proto;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::process::Stdio;
+
+use crate::{Collector, CollectorError, ScriptFormat, ScriptSettings, COLLECTION_INTERVAL};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Label telling which script reported a metric
+const SCRIPT_LABEL: &str = "script";
+
+#[derive(Debug)]
+struct ScriptOutputError(String);
+
+impl fmt::Display for ScriptOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ScriptOutputError {}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonMetricKind {
+    Gauge,
+    Counter,
+}
+
+#[derive(Deserialize)]
+struct JsonMetric {
+    name: String,
+    value: f64,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    kind: Option<JsonMetricKind>,
+}
+
+/// Either `[{"name": "raid_degraded", "value": 0, "labels": {...}}]` or
+/// the shorthand `{"raid_degraded": 0}` for plain gauges
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonOutput {
+    Metrics(Vec<JsonMetric>),
+    Values(HashMap<String, f64>),
+}
+
+fn parse_json(output: &str) -> Result<Vec<proto::Metric>, ScriptOutputError> {
+    let output: JsonOutput = serde_json::from_str(output)
+        .map_err(|e| ScriptOutputError(format!("Invalid JSON: {}", e)))?;
+
+    Ok(match output {
+        JsonOutput::Metrics(metrics) => metrics
+            .into_iter()
+            .map(|metric| proto::Metric {
+                name: metric.name,
+                labels: metric.labels,
+                value: metric.value,
+                unit: metric.unit,
+                kind: match metric.kind {
+                    Some(JsonMetricKind::Counter) => proto::MetricKind::Counter,
+                    _ => proto::MetricKind::Gauge,
+                } as i32,
+            })
+            .collect(),
+        JsonOutput::Values(values) => values
+            .into_iter()
+            .map(|(name, value)| proto::Metric {
+                name,
+                value,
+                ..Default::default()
+            })
+            .collect(),
+    })
+}
+
+/// Splits `a="1",b="x\"y"` into label pairs
+fn parse_prometheus_labels(all_labels: &str) -> Result<HashMap<String, String>, ScriptOutputError> {
+    let invalid = || ScriptOutputError(format!("Invalid labels {{{}}}", all_labels));
+    let mut parsed = HashMap::new();
+
+    let mut labels = all_labels;
+    loop {
+        labels = labels.trim_start_matches([',', ' ']);
+        if labels.is_empty() {
+            return Ok(parsed);
+        }
+        let (key, rest) = labels.split_once('=').ok_or_else(invalid)?;
+        let mut chars = rest.strip_prefix('"').ok_or_else(invalid)?.char_indices();
+
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err(invalid()),
+                },
+                Some((i, '"')) => break i,
+                Some((_, c)) => value.push(c),
+                None => return Err(invalid()),
+            }
+        };
+        parsed.insert(key.trim().to_string(), value);
+        // skip the value and its closing quote
+        labels = &rest[end + 2..];
+    }
+}
+
+/// Parses the Prometheus text format. `# TYPE` comments mark counters,
+/// everything else is reported as a gauge.
+fn parse_prometheus(output: &str) -> Result<Vec<proto::Metric>, ScriptOutputError> {
+    let mut counters = vec![];
+    let mut metrics = vec![];
+
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut words = comment.split_whitespace();
+            if let (Some("TYPE"), Some(name), Some("counter")) =
+                (words.next(), words.next(), words.next())
+            {
+                counters.push(name.to_string());
+            }
+            continue;
+        }
+
+        let invalid = || ScriptOutputError(format!("Invalid line {:?}", line));
+        let (name, labels, rest) = match line.split_once('{') {
+            Some((name, rest)) => {
+                let (labels, rest) = rest.rsplit_once('}').ok_or_else(invalid)?;
+                (name, parse_prometheus_labels(labels)?, rest)
+            }
+            None => {
+                let (name, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                (name, HashMap::new(), rest)
+            }
+        };
+        // an optional timestamp follows the value
+        let value = match rest.split_whitespace().next() {
+            Some("+Inf") => f64::INFINITY,
+            Some("-Inf") => f64::NEG_INFINITY,
+            Some(value) => value.parse().map_err(|_| invalid())?,
+            None => return Err(invalid()),
+        };
+
+        metrics.push(proto::Metric {
+            name: name.trim().to_string(),
+            labels,
+            value,
+            ..Default::default()
+        });
+    }
+
+    for metric in &mut metrics {
+        if counters.contains(&metric.name) {
+            metric.set_kind(proto::MetricKind::Counter);
+        }
+    }
+    Ok(metrics)
+}
+
+fn to_events(metrics: Vec<proto::Metric>) -> Vec<proto::ChangeEvent> {
+    metrics
+        .iter()
+        .map(|metric| proto::ToEvent::to_change_event(metric, proto::EventType::Update))
+        .collect()
+}
+
+/// Runs an executable from the settings and reports its output as
+/// metrics. Each run also reports `teacup_script_up`, 0 with a `reason`
+/// label if the script failed, timed out or printed garbage, and
+/// `teacup_script_duration_seconds`.
+pub struct ScriptCollector {
+    // `script:<name>`, apart from the built-in collectors
+    name: String,
+    settings: ScriptSettings,
+    run: Option<JoinHandle<Vec<proto::ChangeEvent>>>,
+    last_start: Option<Instant>,
+}
+
+impl ScriptCollector {
+    pub fn new(settings: ScriptSettings) -> Self {
+        ScriptCollector {
+            name: format!("script:{}", settings.name),
+            settings,
+            run: None,
+            last_start: None,
+        }
+    }
+}
+
+async fn run_script(settings: ScriptSettings) -> Vec<proto::ChangeEvent> {
+    let start = Instant::now();
+    let result = tokio::time::timeout(
+        Duration::from_secs(settings.timeout),
+        Command::new(&settings.command)
+            .args(&settings.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // a timeout drops the child and thus kills it
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await;
+    let duration = start.elapsed();
+
+    let metrics = match result {
+        Ok(Ok(output)) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            match settings.format {
+                ScriptFormat::Json => parse_json(&stdout),
+                ScriptFormat::Prometheus => parse_prometheus(&stdout),
+            }
+            .map_err(|e| ("output", e.to_string()))
+        }
+        Ok(Ok(output)) => Err((
+            "exit_status",
+            format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        )),
+        Ok(Err(e)) => Err(("spawn", e.to_string())),
+        Err(_) => Err(("timeout", format!("No result after {:?}", duration))),
+    };
+
+    let script_labels = || HashMap::from([(SCRIPT_LABEL.to_string(), settings.name.clone())]);
+    let mut up = proto::Metric {
+        name: "teacup_script_up".to_string(),
+        labels: script_labels(),
+        value: 1.,
+        ..Default::default()
+    };
+    let mut metrics = match metrics {
+        Ok(mut metrics) => {
+            debug!(script = %settings.name, n_metrics = metrics.len(), "Script finished");
+            for metric in &mut metrics {
+                metric
+                    .labels
+                    .insert(SCRIPT_LABEL.to_string(), settings.name.clone());
+            }
+            metrics
+        }
+        Err((reason, error)) => {
+            warn!(script = %settings.name, reason, %error, "Script failed");
+            up.value = 0.;
+            up.labels.insert("reason".to_string(), reason.to_string());
+            vec![]
+        }
+    };
+    metrics.push(up);
+    metrics.push(proto::Metric {
+        name: "teacup_script_duration_seconds".to_string(),
+        labels: script_labels(),
+        value: duration.as_secs_f64(),
+        unit: "s".to_string(),
+        ..Default::default()
+    });
+
+    to_events(metrics)
+}
+
+#[async_trait]
+impl Collector for ScriptCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Scripts run in the background and are checked every tick, so
+    /// that a slow one doesn't hold up the other collectors
+    async fn collect(&mut self) -> Result<Vec<proto::ChangeEvent>, CollectorError> {
+        let mut events = vec![];
+        if let Some(run) = self.run.take() {
+            if !run.is_finished() {
+                self.run = Some(run);
+                return Ok(events);
+            }
+            events = run.await?;
+        }
+
+        // Ticks jitter a bit, half a tick early is on time
+        let interval = Duration::from_secs(self.settings.interval);
+        if self
+            .last_start
+            .is_none_or(|last_start| last_start.elapsed() + COLLECTION_INTERVAL / 2 >= interval)
+        {
+            self.last_start = Some(Instant::now());
+            self.run = Some(tokio::task::spawn(run_script(self.settings.clone())));
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for ScriptCollector {
+    fn drop(&mut self) {
+        if let Some(run) = &self.run {
+            run.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn metric(name: &str, pairs: &[(&str, &str)], value: f64) -> proto::Metric {
+        proto::Metric {
+            name: name.to_string(),
+            labels: labels(pairs),
+            value,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_prometheus_labels() {
+        assert_eq!(parse_prometheus_labels("").unwrap(), labels(&[]));
+        assert_eq!(
+            parse_prometheus_labels(r#"a="1",b="x""#).unwrap(),
+            labels(&[("a", "1"), ("b", "x")])
+        );
+        // the slice after a closing quote at the very end is empty
+        assert_eq!(
+            parse_prometheus_labels(r#"a="""#).unwrap(),
+            labels(&[("a", "")])
+        );
+        assert_eq!(
+            parse_prometheus_labels(r#" a="1", b="2","#).unwrap(),
+            labels(&[("a", "1"), ("b", "2")])
+        );
+        // multi-byte values must not shift the slicing
+        assert_eq!(
+            parse_prometheus_labels(r#"city="Zürich",b="ü""#).unwrap(),
+            labels(&[("city", "Zürich"), ("b", "ü")])
+        );
+    }
+
+    #[test]
+    fn unescapes_prometheus_labels() {
+        assert_eq!(
+            parse_prometheus_labels(r#"a="x\"y",b="1""#).unwrap(),
+            labels(&[("a", "x\"y"), ("b", "1")])
+        );
+        assert_eq!(
+            parse_prometheus_labels(r#"a="back\\slash",b="new\nline""#).unwrap(),
+            labels(&[("a", "back\\slash"), ("b", "new\nline")])
+        );
+        // an escaped quote at the end is not the closing one
+        assert_eq!(
+            parse_prometheus_labels(r#"a="\"""#).unwrap(),
+            labels(&[("a", "\"")])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_prometheus_labels() {
+        for all_labels in [r#"a"#, r#"a=1"#, r#"a="1"#, r#"a="1\"#, r#"a="1\""#] {
+            let error = parse_prometheus_labels(all_labels).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Invalid labels {{{}}}", all_labels)
+            );
+        }
+    }
+
+    #[test]
+    fn parses_prometheus() {
+        let output = r#"
+            # HELP requests_total Requests served
+            # TYPE requests_total counter
+            requests_total{method="get",path="/a}b"} 1027 1395066363000
+            # TYPE queue gauge
+            queue 3
+            latency_bucket{le="+Inf"} +Inf
+            temperature{sensor="x\"y"} -Inf
+        "#;
+
+        let mut requests = metric(
+            "requests_total",
+            &[("method", "get"), ("path", "/a}b")],
+            1027.,
+        );
+        requests.set_kind(proto::MetricKind::Counter);
+        assert_eq!(
+            parse_prometheus(output).unwrap(),
+            vec![
+                requests,
+                metric("queue", &[], 3.),
+                metric("latency_bucket", &[("le", "+Inf")], f64::INFINITY),
+                metric("temperature", &[("sensor", "x\"y")], f64::NEG_INFINITY),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_prometheus() {
+        for line in [
+            "queue",
+            "queue{a=\"1\" 3",
+            "queue{} ",
+            "queue three",
+            "queue{a=1} 3",
+        ] {
+            assert!(parse_prometheus(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn parses_json_metrics() {
+        let output = r#"[
+            {"name": "raid_degraded", "value": 0, "labels": {"array": "md0"}},
+            {"name": "backups", "value": 12, "unit": "count", "kind": "counter"},
+            {"name": "queue", "value": 1.5, "kind": "gauge"}
+        ]"#;
+
+        let mut backups = metric("backups", &[], 12.);
+        backups.unit = "count".to_string();
+        backups.set_kind(proto::MetricKind::Counter);
+        assert_eq!(
+            parse_json(output).unwrap(),
+            vec![
+                metric("raid_degraded", &[("array", "md0")], 0.),
+                backups,
+                metric("queue", &[], 1.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json_values() {
+        let mut metrics = parse_json(r#"{"raid_degraded": 0, "queue": 2.5}"#).unwrap();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            metrics,
+            vec![metric("queue", &[], 2.5), metric("raid_degraded", &[], 0.)]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for output in [
+            "",
+            "{\"queue\": \"high\"}",
+            "[{\"name\": \"queue\"}]",
+            "[{\"name\": \"queue\", \"value\": 1, \"kind\": \"timer\"}]",
+            "3",
+        ] {
+            let error = parse_json(output).unwrap_err();
+            assert!(error.to_string().starts_with("Invalid JSON: "), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// Completes once the process is asked to terminate by SIGINT or
+/// SIGTERM (only Ctrl-C on other platforms).
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                return;
+            }
+            Err(err) => {
+                error!(error = %err, "Failed to listen for SIGTERM");
+            }
+        }
+    }
+
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!(error = %err, "Failed to listen for Ctrl-C");
+        // Without signals we never shut down on our own
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Returns a receiver which switches to `true` once a shutdown
+/// signal arrived so that several tasks can wait for it.
+pub fn spawn_shutdown_listener() -> watch::Receiver<bool> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    tokio::task::spawn(async move {
+        shutdown_signal().await;
+        info!("Received shutdown signal");
+        let _ = shutdown_tx.send(true);
+    });
+
+    shutdown_rx
+}
+
+/// Completes once a shutdown was requested.
+pub async fn wait_for_shutdown(shutdown_rx: &mut watch::Receiver<bool>) {
+    while !*shutdown_rx.borrow() {
+        if shutdown_rx.changed().await.is_err() {
+            // The listener is gone, thus there will be no shutdown
+            std::future::pending::<()>().await;
+        }
+    }
+}